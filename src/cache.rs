@@ -0,0 +1,535 @@
+//! Tools to list, inspect and clean up the incremental caches workers leave behind.
+//!
+//! Each cache is a `rustc-worker-<hash>-<compilation mode>` directory holding one
+//! `<crate>-<hash>` directory per crate, each with rustc's incremental session directories.
+
+use crate::check;
+use crate::invocations;
+use crate::lock;
+use crate::lock::CrateLock;
+use crate::manifest::Manifest;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// A per-user directory for caches, used when the shared temporary directory can't be trusted.
+fn fallback_root() -> Option<PathBuf> {
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_home.join("rustc-worker"))
+}
+
+/// The directories caches are created in, in order of preference.
+fn roots() -> Vec<PathBuf> {
+    let mut roots = vec![std::env::temp_dir()];
+    roots.extend(fallback_root());
+    roots
+}
+
+/// Checks that only the current user can write to a directory. A directory from an older
+/// worker that is merely too permissive is fixed up.
+#[cfg(unix)]
+fn check_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let untrusted = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} {}", dir.display(), reason),
+        )
+    };
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if metadata.file_type().is_symlink() {
+        return Err(untrusted("is a symlink".to_string()));
+    }
+    if !metadata.is_dir() {
+        return Err(untrusted("is not a directory".to_string()));
+    }
+    let uid = unsafe { libc::getuid() };
+    if metadata.uid() != uid {
+        return Err(untrusted(format!(
+            "is owned by uid {}, not {}",
+            metadata.uid(),
+            uid
+        )));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates a directory only the current user can access, or checks an existing one.
+fn create_private(dir: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    check_private(dir)
+}
+
+/// Creates the cache directory called `name`, in the shared temporary directory if it can be
+/// trusted there and in a per-user directory otherwise. Another user on the host could have
+/// created the path in the temporary directory first, or planted a symlink there.
+pub(crate) fn create(name: &str) -> io::Result<PathBuf> {
+    let mut errors = Vec::new();
+    for (i, root) in roots().into_iter().enumerate() {
+        // The temporary directory exists already, but the per-user one may not.
+        if i > 0 {
+            if let Err(e) = std::fs::create_dir_all(&root).and_then(|_| check_private(&root)) {
+                errors.push(e.to_string());
+                continue;
+            }
+        }
+        let dir = root.join(name);
+        match create_private(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) => {
+                eprintln!("rustc-worker: not using {}: {}", dir.display(), e);
+                errors.push(e.to_string());
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("no trusted cache directory: {}", errors.join("; ")),
+    ))
+}
+
+/// Creates the cache directory called `name` in `root`, which the user chose.
+pub(crate) fn create_in(root: &Path, name: &str) -> io::Result<PathBuf> {
+    let dir = root.join(name);
+    create_private(&dir)?;
+    Ok(dir)
+}
+
+/// Whether a directory name looks like `rustc-worker-<hash>-<compilation mode>`.
+fn is_cache_name(name: &str) -> bool {
+    let mut parts = name
+        .strip_prefix("rustc-worker-")
+        .unwrap_or("")
+        .splitn(2, '-');
+    let hash = parts.next().unwrap_or("");
+    let mode = parts.next().unwrap_or("");
+    !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_digit()) && !mode.is_empty()
+}
+
+/// Finds every cache directory.
+pub(crate) fn discover() -> io::Result<Vec<PathBuf>> {
+    let mut caches = Vec::new();
+    for root in roots() {
+        let entries = match std::fs::read_dir(&root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let is_cache = entry.file_name().to_str().map_or(false, is_cache_name);
+            if is_cache && entry.file_type()?.is_dir() {
+                caches.push(entry.path());
+            }
+        }
+    }
+    caches.sort();
+    Ok(caches)
+}
+
+/// Total size and latest modification time of everything under a path, without following
+/// symlinks.
+fn usage(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let mut size = metadata.len();
+    let mut modified = metadata.modified()?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let (entry_size, entry_modified) = usage(&entry?.path())?;
+            size += entry_size;
+            modified = modified.max(entry_modified);
+        }
+    }
+    Ok((size, modified))
+}
+
+struct Crate {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+fn crates(cache: &Path) -> io::Result<Vec<Crate>> {
+    let mut crates = Vec::new();
+    for entry in std::fs::read_dir(cache)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_dir() || name == invocations::DIR || name == check::DIR {
+            continue;
+        }
+        let (size, last_used) = usage(&entry.path())?;
+        crates.push(Crate {
+            path: entry.path(),
+            size,
+            last_used,
+        });
+    }
+    crates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(crates)
+}
+
+/// The crates of a cache and those `rustc-worker check` keeps in its own incremental directory
+/// in it, which take up space and go stale the same way.
+fn prunable(cache: &Path) -> io::Result<Vec<Crate>> {
+    let mut all = crates(cache)?;
+    match crates(&cache.join(check::DIR)) {
+        Ok(checked) => all.extend(checked),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(all)
+}
+
+/// The directories rustc keeps the sessions of a crate in. There is one per crate with that
+/// name, named `<crate name>-<stable crate id>`.
+fn crate_dirs(cache: &Path, crate_name: &str) -> io::Result<Vec<PathBuf>> {
+    Ok(crates(cache)?
+        .into_iter()
+        .map(|krate| krate.path)
+        .filter(|path| {
+            let dir_name = path.file_name().unwrap_or_default().to_string_lossy();
+            dir_name.rfind('-').map(|at| &dir_name[..at]) == Some(crate_name)
+        })
+        .collect())
+}
+
+/// Whether rustc finished a session for the crate that the next compilation can start from.
+pub(crate) fn has_session(cache: &Path, crate_name: &str) -> bool {
+    let dirs = crate_dirs(cache, crate_name).unwrap_or_default();
+    dirs.iter().any(|dir| {
+        std::fs::read_dir(dir).into_iter().flatten().any(|session| {
+            session.map_or(false, |session| {
+                let name = session.file_name().to_string_lossy().into_owned();
+                name.starts_with("s-") && !name.ends_with("-working") && !name.ends_with(".lock")
+            })
+        })
+    })
+}
+
+/// Removes the sessions rustc left half-written in a cache when it was killed while compiling
+/// `crate_name`, so the next compilation doesn't have to clean up after it. Returns how many
+/// were removed.
+pub(crate) fn remove_working_sessions(cache: &Path, crate_name: &str) -> io::Result<usize> {
+    let mut removed = 0;
+    for dir in crate_dirs(cache, crate_name)? {
+        for session in std::fs::read_dir(&dir)? {
+            let session = session?;
+            let name = session.file_name().to_string_lossy().into_owned();
+            // `s-<timestamp>-<random>-working`, locked by `s-<timestamp>-<random>.lock`.
+            if let Some(stem) = name.strip_suffix("-working") {
+                std::fs::remove_dir_all(session.path())?;
+                let _ = std::fs::remove_file(dir.join(format!("{}.lock", stem)));
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// The rustc a cache was created for, if the worker that created it recorded one.
+fn toolchain(cache: &Path) -> Option<PathBuf> {
+    Manifest::load(cache).map(|manifest| manifest.rustc)
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn compilation_mode(cache: &Path) -> String {
+    let name = cache.file_name().unwrap_or_default().to_string_lossy();
+    name.splitn(4, '-').nth(3).unwrap_or("").to_string()
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        s if s < 60 => format!("{}s ago", s),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (24 * 60 * 60)),
+    }
+}
+
+fn describe_toolchain(cache: &Path) -> String {
+    match toolchain(cache) {
+        Some(rustc) if rustc.exists() => rustc.display().to_string(),
+        Some(rustc) => format!("{} (no longer installed)", rustc.display()),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Prints what the manifest of a cache says about it.
+fn describe<W: io::Write>(
+    cache: &Path,
+    manifest: Option<&Manifest>,
+    out: &mut W,
+) -> io::Result<()> {
+    writeln!(out, "{}", cache.display())?;
+    writeln!(out, "  toolchain:        {}", describe_toolchain(cache))?;
+    let version = manifest
+        .and_then(|manifest| manifest.rustc_version.as_ref())
+        .and_then(|version| version.lines().next());
+    if let Some(version) = version {
+        writeln!(out, "  rustc version:    {}", version)?;
+    }
+    writeln!(out, "  compilation mode: {}", compilation_mode(cache))?;
+    if let Some(manifest) = manifest {
+        if let Some(execroot) = &manifest.execroot {
+            writeln!(out, "  execroot:         {}", execroot.display())?;
+        }
+        if let Some(output_base) = &manifest.output_base {
+            writeln!(out, "  output base:      {}", output_base.display())?;
+        }
+        writeln!(out, "  worker version:   {}", manifest.worker_version)?;
+        writeln!(
+            out,
+            "  created:          {}",
+            format_age(from_secs(manifest.created))
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints every cache with its toolchain, compilation mode, size, crate count and last use.
+pub fn list<W: io::Write>(out: &mut W) -> io::Result<()> {
+    for cache in discover()? {
+        let manifest = Manifest::load(&cache);
+        let crates = crates(&cache)?;
+        let (size, modified) = usage(&cache)?;
+        let last_used = manifest
+            .as_ref()
+            .and_then(Manifest::last_used)
+            .map_or(modified, from_secs);
+        describe(&cache, manifest.as_ref(), out)?;
+        writeln!(out, "  size:             {}", format_size(size))?;
+        writeln!(out, "  crates:           {}", crates.len())?;
+        writeln!(out, "  last used:        {}", format_age(last_used))?;
+    }
+    Ok(())
+}
+
+/// Prints the incremental sessions of each crate in a cache.
+pub fn inspect<W: io::Write>(cache: &Path, out: &mut W) -> io::Result<()> {
+    let manifest = Manifest::load(cache);
+    describe(cache, manifest.as_ref(), out)?;
+    for krate in crates(cache)? {
+        let dir_name = krate.path.file_name().unwrap_or_default().to_string_lossy();
+        // rustc names the directory `<crate name>-<stable crate id>`.
+        let crate_name = dir_name.rsplitn(2, '-').last().unwrap_or_default();
+        let last_used = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.crate_last_used(crate_name))
+            .map_or(krate.last_used, from_secs);
+        writeln!(
+            out,
+            "{}: {}, last used {}",
+            dir_name,
+            format_size(krate.size),
+            format_age(last_used)
+        )?;
+        let mut sessions: Vec<_> = std::fs::read_dir(&krate.path)?.collect::<Result<_, _>>()?;
+        sessions.sort_by_key(|entry| entry.file_name());
+        for session in sessions {
+            let (size, modified) = usage(&session.path())?;
+            writeln!(
+                out,
+                "  {}: {}, modified {}",
+                session.file_name().to_string_lossy(),
+                format_size(size),
+                format_age(modified)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Locks a crate from `crates` so that no worker compiles it while it is removed. `None` if
+/// a worker is compiling it right now.
+fn lock_crate(path: &Path) -> io::Result<Option<CrateLock>> {
+    let dir_name = path.file_name().unwrap_or_default().to_string_lossy();
+    // rustc names the directory `<crate name>-<stable crate id>`.
+    let crate_name = dir_name.rsplitn(2, '-').last().unwrap_or_default();
+    let cache = path.parent().unwrap_or(path);
+    lock::acquire(cache, crate_name, Duration::from_secs(0))
+}
+
+/// Locks every crate in a cache, or returns `None` if a worker is compiling one of them.
+fn lock_cache(cache: &Path) -> io::Result<Option<Vec<CrateLock>>> {
+    let mut locks = Vec::new();
+    for krate in prunable(cache)? {
+        match lock_crate(&krate.path)? {
+            Some(lock) => locks.push(lock),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(locks))
+}
+
+fn remove<W: io::Write>(path: &Path, reason: &str, dry_run: bool, out: &mut W) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}{} ({})",
+        if dry_run {
+            "would remove "
+        } else {
+            "removing "
+        },
+        path.display(),
+        reason
+    )?;
+    if !dry_run {
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+/// Removes crates unused for longer than `max_age`, then the least recently used crates
+/// until all caches together fit in `max_size` bytes.
+pub fn prune<W: io::Write>(
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    dry_run: bool,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut all_crates = Vec::new();
+    for cache in discover()? {
+        all_crates.extend(prunable(&cache)?);
+    }
+    // Oldest first.
+    all_crates.sort_by_key(|krate| krate.last_used);
+    let now = SystemTime::now();
+    let mut total: u64 = all_crates.iter().map(|krate| krate.size).sum();
+    for krate in all_crates {
+        let age = now.duration_since(krate.last_used).unwrap_or_default();
+        let reason = if max_age.map_or(false, |max_age| age > max_age) {
+            format!("last used {}", format_age(krate.last_used))
+        } else if max_size.map_or(false, |max_size| total > max_size) {
+            format!("caches exceed {}", format_size(max_size.unwrap()))
+        } else {
+            continue;
+        };
+        let _lock = match lock_crate(&krate.path)? {
+            Some(lock) => lock,
+            None => {
+                writeln!(out, "skipping {} (in use)", krate.path.display())?;
+                continue;
+            }
+        };
+        remove(&krate.path, &reason, dry_run, out)?;
+        total -= krate.size;
+    }
+    Ok(())
+}
+
+/// Removes the caches of toolchains that are no longer installed, or every cache with `all`.
+pub fn clean<W: io::Write>(all: bool, dry_run: bool, out: &mut W) -> io::Result<()> {
+    for cache in discover()? {
+        let reason = match toolchain(&cache) {
+            _ if all => "all caches".to_string(),
+            Some(rustc) if !rustc.exists() => {
+                format!("{} is no longer installed", rustc.display())
+            }
+            _ => continue,
+        };
+        let _locks = match lock_cache(&cache)? {
+            Some(locks) => locks,
+            None => {
+                writeln!(out, "skipping {} (in use)", cache.display())?;
+                continue;
+            }
+        };
+        remove(&cache, &reason, dry_run, out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_names() {
+        assert!(is_cache_name("rustc-worker-1234-fastbuild"));
+        assert!(is_cache_name("rustc-worker-1234-opt"));
+        assert!(!is_cache_name("rustc-worker-dep-info-12-0.d"));
+        assert!(!is_cache_name("rustc-worker-1234"));
+        assert!(!is_cache_name("other-1234-opt"));
+        assert_eq!(
+            compilation_mode(Path::new("/tmp/rustc-worker-1234-fastbuild")),
+            "fastbuild"
+        );
+    }
+
+    #[test]
+    fn test_lock_crate() {
+        let cache = std::env::temp_dir().join(format!(
+            "rustc-worker-lock-crate-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(cache.join("foo-1a2b")).unwrap();
+        std::fs::create_dir_all(cache.join("bar-3c4d")).unwrap();
+        let held = lock::acquire(&cache, "foo", Duration::from_secs(0)).unwrap();
+        assert!(held.is_some());
+        assert!(lock_crate(&cache.join("foo-1a2b")).unwrap().is_none());
+        assert!(lock_crate(&cache.join("bar-3c4d")).unwrap().is_some());
+        assert!(lock_cache(&cache).unwrap().is_none());
+        drop(held);
+        assert!(lock_cache(&cache).unwrap().is_some());
+        // `rustc-worker check` locks its crates in its own directory.
+        let check = cache.join(check::DIR);
+        std::fs::create_dir_all(check.join("foo-1a2b")).unwrap();
+        let held = lock::acquire(&check, "foo", Duration::from_secs(0)).unwrap();
+        assert!(lock_cache(&cache).unwrap().is_none());
+        drop(held);
+        assert_eq!(prunable(&cache).unwrap().len(), 3);
+        std::fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(12), "12 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -0,0 +1,200 @@
+//! `rustc-worker check`: type-checks a crate outside of Bazel, for rust-analyzer's flycheck.
+//!
+//! The last invocation the worker recorded for the crate is run again in the same execroot,
+//! against the dependencies Bazel built, with `--emit=metadata` and JSON diagnostics. The
+//! diagnostics are printed to stdout one per line, which is what flycheck reads; the outputs go
+//! to a scratch directory, so Bazel's are left alone.
+//!
+//! rustc starts over whenever `--emit` differs from the session it would continue, so a check
+//! continuing the sessions of Bazel's builds would throw them away, and the next build would be
+//! as slow as a clean one. Checks keep their own sessions in the `check` directory of the same
+//! cache instead: the first check of a crate starts from nothing, and the ones after it are
+//! incremental.
+
+use crate::cache;
+use crate::diagnostics;
+use crate::invocations;
+use crate::invocations::Invocation;
+use crate::json;
+use crate::json::Value;
+use crate::locations::Locations;
+use crate::lock;
+use crate::lock::PrivateDir;
+use crate::manifest::Manifest;
+use crate::rustc_args::RustcArgs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The directory in the cache the sessions of checks are kept in.
+pub(crate) const DIR: &str = "check";
+
+/// How long to wait for another check of the same crate. rust-analyzer kills a check when it
+/// starts the next one, so the lock is normally released right away.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The latest invocation of the crate in any of the caches, with the cache it was recorded in.
+fn find(caches: &[PathBuf], crate_name: &str) -> io::Result<Option<(PathBuf, Invocation)>> {
+    let mut found = Vec::new();
+    for cache in caches {
+        for invocation in invocations::load(cache)? {
+            if invocation.crate_name == crate_name {
+                found.push((cache.clone(), invocation));
+            }
+        }
+    }
+    Ok(found
+        .into_iter()
+        .max_by_key(|(_, invocation)| invocation.time))
+}
+
+/// The command line of the invocation with metadata as the only output, written to `scratch`,
+/// and without the error format, which the check chooses. `None` if rustc would write to the
+/// working directory.
+fn check_args(args: &[String], scratch: &Path) -> Option<Vec<String>> {
+    let args = RustcArgs::new(args).without("--emit");
+    let args = RustcArgs::new(&args).without("--error-format");
+    let args = RustcArgs::new(&args).without("--json");
+    let mut args = RustcArgs::new(&args).redirect_outputs(scratch)?.args;
+    args.push("--emit=metadata".to_string());
+    Some(args)
+}
+
+/// Rewrites the file names in a diagnostic, its spans, macro expansions and children, and the
+/// locations in its rendered text.
+fn rewrite(value: &mut Value, locations: &Locations) {
+    match value {
+        Value::Object(entries) => {
+            for (key, value) in entries {
+                match (key.as_str(), value) {
+                    ("file_name", Value::String(path)) => {
+                        if let Some(rewritten) = locations.rewrite_path(path) {
+                            *path = rewritten.to_string_lossy().into_owned();
+                        }
+                    }
+                    ("rendered", Value::String(text)) => *text = locations.rewrite(text),
+                    (_, value) => rewrite(value, locations),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                rewrite(value, locations);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Checks the crate, compiled with one of the given caches or any cache when none are given,
+/// and writes its diagnostics to `out`. Returns rustc's exit code.
+pub fn check<W: io::Write>(
+    crate_name: &str,
+    caches: &[PathBuf],
+    workspace_root: Option<&Path>,
+    out: &mut W,
+) -> io::Result<i32> {
+    let caches = if caches.is_empty() {
+        cache::discover()?
+    } else {
+        caches.to_vec()
+    };
+    let (cache, invocation) = find(&caches, crate_name)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("the worker has not compiled {}", crate_name),
+        )
+    })?;
+    let manifest = Manifest::load(&cache).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no manifest", cache.display()),
+        )
+    })?;
+    let scratch = PrivateDir::create()?;
+    let args = check_args(&invocation.args, scratch.path()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} writes its outputs to the working directory", crate_name),
+        )
+    })?;
+    let incremental_dir = cache.join(DIR);
+    std::fs::create_dir_all(&incremental_dir)?;
+    let _lock = lock::acquire(&incremental_dir, crate_name, LOCK_TIMEOUT)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("another check of {} is still running", crate_name),
+        )
+    })?;
+
+    let mut cmd = std::process::Command::new(&manifest.rustc);
+    cmd.args(&args);
+    let mut incremental_arg = std::ffi::OsString::from("incremental=");
+    incremental_arg.push(&incremental_dir);
+    cmd.arg("--codegen");
+    cmd.arg(incremental_arg);
+    diagnostics::prepare(&RustcArgs::new(&args), &mut cmd);
+    cmd.current_dir(&invocation.cwd);
+    cmd.envs(invocation.env.iter().map(|(name, value)| (name, value)));
+    let output = cmd.output()?;
+
+    let locations = workspace_root.map(|workspace_root| {
+        Locations::new(
+            workspace_root.to_owned(),
+            invocation.cwd.clone(),
+            manifest.output_base.clone().unwrap_or_default(),
+        )
+    });
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stderr.lines() {
+        let mut diagnostic = match json::parse(line) {
+            Ok(diagnostic) if line.starts_with('{') => diagnostic,
+            // Not a diagnostic, like the message of an internal compiler error.
+            _ => {
+                eprintln!("{}", line);
+                continue;
+            }
+        };
+        let message_type = diagnostic.get("$message_type").and_then(Value::as_str);
+        if message_type.map_or(false, |t| t != "diagnostic") {
+            continue;
+        }
+        if let Some(locations) = &locations {
+            rewrite(&mut diagnostic, locations);
+        }
+        writeln!(out, "{}", diagnostic)?;
+    }
+    Ok(crate::process_exit_code(output.status))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_args() {
+        let args: Vec<String> = [
+            "--crate-name=foo",
+            "--emit=dep-info,link",
+            "--error-format=human",
+            "--out-dir",
+            "bazel-out/bin/foo",
+            "src/lib.rs",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        assert_eq!(
+            check_args(&args, Path::new("/scratch")).unwrap(),
+            [
+                "--crate-name=foo",
+                "--out-dir",
+                "/scratch/0",
+                "src/lib.rs",
+                "--emit=metadata"
+            ]
+        );
+        assert!(check_args(&args[..3], Path::new("/scratch")).is_none());
+    }
+}
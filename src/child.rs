@@ -0,0 +1,279 @@
+//! Running rustc for a request, killing it when it takes too long, and measuring what it cost.
+
+use std::io;
+use std::io::Read;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The exit code of requests that timed out, the same as `timeout(1)` uses.
+pub(crate) const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How long to keep reading rustc's output after killing it. A process that left its process
+/// group can hold the pipes open forever.
+const KILLED_OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// What running rustc cost, from `wait4`. CPU time, memory and I/O of processes rustc started
+/// and waited for, like the linker, are included.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Usage {
+    pub(crate) wall: Duration,
+    pub(crate) user: Duration,
+    pub(crate) system: Duration,
+    /// Peak resident set size of the largest process, in bytes.
+    pub(crate) max_rss: u64,
+    /// Blocks read from and written to disk.
+    pub(crate) block_in: u64,
+    pub(crate) block_out: u64,
+}
+
+/// How rustc finished.
+pub(crate) struct Finished {
+    pub(crate) output: Output,
+    /// Whether rustc was killed for running longer than its timeout.
+    pub(crate) timed_out: bool,
+    pub(crate) usage: Usage,
+}
+
+/// Puts rustc in a process group of its own, so that a timeout kills the linker and anything
+/// else it started along with it.
+#[cfg(unix)]
+fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_cmd: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(_pid: u32) {}
+
+#[cfg(unix)]
+fn duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+/// Waits for the child to exit without reaping it, so that its pid, and the process group named
+/// after it, can't be reused yet.
+#[cfg(unix)]
+fn wait_exited(child: &std::process::Child) -> io::Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let waited = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if waited == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_exited(_child: &std::process::Child) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reaps the child with `wait4`, which also returns its resource usage.
+#[cfg(unix)]
+fn wait(child: &mut std::process::Child) -> io::Result<(ExitStatus, Usage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut rusage) };
+        if pid >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    // Linux reports kilobytes, macOS bytes.
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let usage = Usage {
+        wall: Duration::default(),
+        user: duration(rusage.ru_utime),
+        system: duration(rusage.ru_stime),
+        max_rss: rusage.ru_maxrss as u64 * rss_unit,
+        block_in: rusage.ru_inblock as u64,
+        block_out: rusage.ru_oublock as u64,
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+#[cfg(not(unix))]
+fn wait(child: &mut std::process::Child) -> io::Result<(ExitStatus, Usage)> {
+    Ok((child.wait()?, Usage::default()))
+}
+
+/// A pipe being read to the end on a thread, so that rustc never blocks on a full pipe while we
+/// wait for it.
+struct Reader {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<io::Result<()>>,
+}
+
+impl Reader {
+    fn spawn<R: Read + Send + 'static>(pipe: Option<R>) -> Self {
+        let bytes = Arc::new(Mutex::new(Vec::new()));
+        let (sender, done) = mpsc::channel();
+        let read = Arc::clone(&bytes);
+        std::thread::spawn(move || {
+            let result = (|| {
+                let mut pipe = match pipe {
+                    Some(pipe) => pipe,
+                    None => return Ok(()),
+                };
+                let mut buffer = [0; 8192];
+                loop {
+                    match pipe.read(&mut buffer) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => read
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .extend_from_slice(&buffer[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            })();
+            let _ = sender.send(result);
+        });
+        Reader { bytes, done }
+    }
+
+    /// Waits for the end of the output, or at most until `deadline`, and returns what was read.
+    fn finish(self, deadline: Option<Instant>) -> io::Result<Vec<u8>> {
+        let done = match deadline {
+            Some(deadline) => self
+                .done
+                .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => self.done.recv().map_err(mpsc::RecvTimeoutError::from),
+        };
+        match done {
+            // Whatever still holds the pipe keeps the thread reading it, into a buffer
+            // nobody looks at anymore.
+            Ok(Ok(())) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "output reader panicked",
+                ))
+            }
+        }
+        let mut bytes = self.bytes.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(std::mem::take(&mut *bytes))
+    }
+}
+
+/// Runs the command to completion and collects its output, killing its process group if it
+/// runs longer than `timeout`.
+pub(crate) fn run(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Finished> {
+    if timeout.is_some() {
+        own_process_group(cmd);
+    }
+    let start = Instant::now();
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = Reader::spawn(child.stdout.take());
+    let stderr = Reader::spawn(child.stderr.take());
+    let pid = child.id();
+    // Set once rustc has exited, before it is reaped. The watchdog only kills while it isn't,
+    // so it never kills a process group that reused rustc's pid.
+    let reaped = Arc::new(Mutex::new(false));
+    let (finished, watchdog) = mpsc::channel::<()>();
+    let timer = timeout.map(|timeout| {
+        let reaped = Arc::clone(&reaped);
+        std::thread::spawn(move || match watchdog.recv_timeout(timeout) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let reaped = reaped.lock().unwrap_or_else(PoisonError::into_inner);
+                if !*reaped {
+                    kill_process_group(pid);
+                }
+                !*reaped
+            }
+            _ => false,
+        })
+    });
+    let exited = wait_exited(&child);
+    *reaped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+    drop(finished);
+    let waited = exited.and_then(|()| wait(&mut child));
+    let wall = start.elapsed();
+    let timed_out = timer.map_or(false, |timer| timer.join().unwrap_or(false));
+    let (status, mut usage) = waited?;
+    usage.wall = wall;
+    let deadline = if timed_out {
+        Some(Instant::now() + KILLED_OUTPUT_GRACE)
+    } else {
+        None
+    };
+    let output = Output {
+        status,
+        stdout: stdout.finish(deadline)?,
+        stderr: stderr.finish(deadline)?,
+    };
+    Ok(Finished {
+        // rustc may have finished just as the timer fired.
+        timed_out: timed_out && !output.status.success(),
+        output,
+        usage,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timeout_with_escaped_grandchild() {
+        // The grandchild leaves the process group, so it survives the kill and keeps the
+        // pipes open.
+        let mut cmd = Command::new("sh");
+        cmd.args(&["-c", "echo started; setsid sleep 5 & sleep 30"]);
+        let start = Instant::now();
+        let finished = run(&mut cmd, Some(Duration::from_millis(200))).unwrap();
+        assert!(finished.timed_out);
+        assert_eq!(finished.output.stdout, b"started\n");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,155 @@
+//! A client that drives a persistent worker the way Bazel does, for tests, benchmarks and
+//! debugging without Bazel.
+
+use crate::worker_protocol::WorkRequest;
+use crate::worker_protocol::WorkResponse;
+use protobuf::CodedInputStream;
+use protobuf::CodedOutputStream;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::io::Write;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+pub struct WorkerClient {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    responses: mpsc::Receiver<io::Result<WorkResponse>>,
+    /// Responses that arrived while waiting for another request.
+    received: HashMap<i32, WorkResponse>,
+}
+
+fn to_io_error(e: protobuf::ProtobufError) -> io::Error {
+    match e {
+        protobuf::ProtobufError::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl WorkerClient {
+    /// Starts `program` with its startup arguments followed by `--persistent_worker`.
+    pub fn spawn<P, I, S>(program: P, startup_args: I) -> io::Result<Self>
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut child = Command::new(program)
+            .args(startup_args)
+            .arg("--persistent_worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let (sender, responses) = mpsc::channel();
+        // Responses are read on a thread so that waiting for one can time out.
+        std::thread::spawn(move || {
+            let mut stream = CodedInputStream::new(&mut stdout);
+            loop {
+                let response = (|| {
+                    let len = stream.read_raw_varint32()?;
+                    let limit = stream.push_limit(u64::from(len))?;
+                    let mut response = WorkResponse::default();
+                    response.merge_from(&mut stream)?;
+                    stream.pop_limit(limit);
+                    Ok(response)
+                })()
+                .map_err(to_io_error);
+                let failed = response.is_err();
+                if sender.send(response).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(WorkerClient {
+            child,
+            stdin,
+            responses,
+            received: HashMap::new(),
+        })
+    }
+
+    /// Sends a request without waiting for its response.
+    pub fn send(&mut self, request: &WorkRequest) -> io::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "worker stdin is closed"))?;
+        let mut bytes = Vec::new();
+        {
+            let mut stream = CodedOutputStream::vec(&mut bytes);
+            stream
+                .write_raw_varint32(request.compute_size())
+                .map_err(to_io_error)?;
+            request
+                .write_to_with_cached_sizes(&mut stream)
+                .map_err(to_io_error)?;
+            stream.flush().map_err(to_io_error)?;
+        }
+        stdin.write_all(&bytes)?;
+        stdin.flush()
+    }
+
+    /// Waits for the response to `request_id`, keeping responses to other requests for later.
+    pub fn wait(&mut self, request_id: i32, timeout: Duration) -> io::Result<WorkResponse> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(response) = self.received.remove(&request_id) {
+                return Ok(response);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let response = match self.responses.recv_timeout(remaining) {
+                Ok(response) => response?,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no response to request {} after {:?}", request_id, timeout),
+                    ))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "worker closed its stdout",
+                    ))
+                }
+            };
+            self.received.insert(response.get_request_id(), response);
+        }
+    }
+
+    /// Sends a request and waits for its response.
+    pub fn request(
+        &mut self,
+        request: &WorkRequest,
+        timeout: Duration,
+    ) -> io::Result<WorkResponse> {
+        self.send(request)?;
+        self.wait(request.get_request_id(), timeout)
+    }
+
+    /// Closes the worker's stdin, as Bazel does when shutting a worker down, and waits for it
+    /// to exit.
+    pub fn shutdown(mut self) -> io::Result<ExitStatus> {
+        self.stdin.take();
+        self.child.wait()
+    }
+}
+
+impl Drop for WorkerClient {
+    fn drop(&mut self) {
+        // Don't leave a worker behind when a test fails before shutting it down.
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Decoding of captured worker traffic into JSON, for debugging the protocol.
+
+use crate::json::Value;
+use crate::protocol_json;
+use crate::worker_protocol::WorkRequest;
+use crate::worker_protocol::WorkResponse;
+use protobuf::Message;
+use std::convert::TryFrom;
+use std::io;
+
+/// Which side of the worker a captured stream comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    /// The worker's stdin.
+    Requests,
+    /// The worker's stdout.
+    Responses,
+}
+
+impl std::str::FromStr for Stream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "requests" => Ok(Stream::Requests),
+            "responses" => Ok(Stream::Responses),
+            _ => Err(format!(
+                "unknown stream {:?}, expected requests or responses",
+                s
+            )),
+        }
+    }
+}
+
+impl Stream {
+    /// The fields of the message with their wire types.
+    fn known_fields(self) -> &'static [(u32, u32)] {
+        match self {
+            // arguments, inputs, request_id, cancel, verbosity, sandbox_dir
+            Stream::Requests => &[(1, 2), (2, 2), (3, 0), (4, 0), (5, 0), (6, 2)],
+            // exit_code, output, request_id, was_cancelled
+            Stream::Responses => &[(1, 0), (2, 2), (3, 0), (4, 0)],
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Requests => "request",
+            Stream::Responses => "response",
+        }
+    }
+
+    fn to_json(self, bytes: &[u8]) -> protobuf::ProtobufResult<Value> {
+        Ok(match self {
+            Stream::Requests => {
+                let mut request = WorkRequest::default();
+                request.merge_from_bytes(bytes)?;
+                protocol_json::request_to_json(&request)
+            }
+            Stream::Responses => {
+                let mut response = WorkResponse::default();
+                response.merge_from_bytes(bytes)?;
+                protocol_json::response_to_json(&response)
+            }
+        })
+    }
+}
+
+/// Reads a varint at `pos`, returning it and the position after it.
+fn read_varint(bytes: &[u8], mut pos: usize) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes.get(pos)?;
+        pos += 1;
+        value |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some((value, pos));
+        }
+    }
+    None
+}
+
+/// Returns the offsets and numbers of fields the protocol does not define. `base` is the
+/// offset of the message in the stream.
+fn unknown_fields(
+    stream: Stream,
+    message: &[u8],
+    base: usize,
+) -> Result<Vec<(usize, u32)>, String> {
+    let mut unknown = Vec::new();
+    let mut pos = 0;
+    while pos < message.len() {
+        let start = pos;
+        let (tag, next) = read_varint(message, pos)
+            .ok_or_else(|| format!("byte {}: malformed field tag", base + start))?;
+        pos = next;
+        let field = (tag >> 3) as u32;
+        let wire_type = (tag & 7) as u32;
+        // Lengths come from the stream, so a corrupt one must not overflow.
+        let end = match wire_type {
+            0 => Some(
+                read_varint(message, pos)
+                    .ok_or_else(|| format!("byte {}: malformed varint", base + pos))?
+                    .1,
+            ),
+            1 => pos.checked_add(8),
+            2 => {
+                let (len, next) = read_varint(message, pos)
+                    .ok_or_else(|| format!("byte {}: malformed length", base + pos))?;
+                usize::try_from(len)
+                    .ok()
+                    .and_then(|len| next.checked_add(len))
+            }
+            5 => pos.checked_add(4),
+            _ => {
+                return Err(format!(
+                    "byte {}: unsupported wire type {} for field {}",
+                    base + start,
+                    wire_type,
+                    field
+                ))
+            }
+        };
+        pos = end
+            .filter(|end| *end <= message.len())
+            .ok_or_else(|| format!("byte {}: field {} is truncated", base + start, field))?;
+        if !stream.known_fields().contains(&(field, wire_type)) {
+            unknown.push((base + start, field));
+        }
+    }
+    Ok(unknown)
+}
+
+/// Prints each length-delimited message in `input` as JSON to `out`, and problems with the
+/// stream to `err`. Returns whether the whole stream decoded cleanly.
+pub fn decode<R: io::Read, W: io::Write, E: io::Write>(
+    stream: Stream,
+    input: &mut R,
+    out: &mut W,
+    err: &mut E,
+) -> io::Result<bool> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    let mut clean = true;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let offset = pos;
+        let (len, start) = match read_varint(&bytes, pos) {
+            Some(prefix) => prefix,
+            None => {
+                writeln!(err, "byte {}: truncated or malformed length prefix", offset)?;
+                return Ok(false);
+            }
+        };
+        let end = start.saturating_add(len as usize);
+        if end > bytes.len() {
+            writeln!(
+                err,
+                "byte {}: truncated message, expected {} bytes but only {} remain",
+                offset,
+                len,
+                bytes.len() - start
+            )?;
+            return Ok(false);
+        }
+        let message = &bytes[start..end];
+        pos = end;
+
+        match unknown_fields(stream, message, start) {
+            Ok(unknown) => {
+                for (field_offset, field) in unknown {
+                    clean = false;
+                    writeln!(err, "byte {}: unknown field {}", field_offset, field)?;
+                }
+            }
+            Err(e) => {
+                clean = false;
+                writeln!(err, "{}", e)?;
+            }
+        }
+        match stream.to_json(message) {
+            Ok(value) => {
+                let value = Value::object()
+                    .with("offset", offset)
+                    .with("length", len)
+                    .with(stream.name(), value);
+                writeln!(out, "{}", value.pretty())?;
+            }
+            Err(e) => {
+                clean = false;
+                writeln!(err, "byte {}: could not decode message: {}", offset, e)?;
+            }
+        }
+    }
+    Ok(clean)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // A response with exit_code 1 and unknown field 5, then a truncated message.
+        let bytes = [4, 0x08, 0x01, 0x28, 0x05, 10, 0x08];
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let clean = decode(Stream::Responses, &mut &bytes[..], &mut out, &mut err).unwrap();
+        assert!(!clean);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\"exit_code\": 1"), "{}", out);
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "byte 3: unknown field 5\n\
+             byte 5: truncated message, expected 10 bytes but only 1 remain\n"
+        );
+    }
+
+    #[test]
+    fn test_known_fields() {
+        // A request with cancel, verbosity and sandbox_dir, and a response with was_cancelled.
+        let request = [0x18, 0x01, 0x20, 0x01, 0x28, 0x0a, 0x32, 0x01, b'x'];
+        assert_eq!(
+            unknown_fields(Stream::Requests, &request, 0),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            unknown_fields(Stream::Responses, &[0x20, 0x01], 0),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_truncated_field() {
+        // Field 2 with a length close to 2^64, and field 1 with only half of its fixed64.
+        let huge = [
+            0x12, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+        ];
+        assert_eq!(
+            unknown_fields(Stream::Responses, &huge, 10),
+            Err("byte 10: field 2 is truncated".to_string())
+        );
+        assert_eq!(
+            unknown_fields(Stream::Responses, &[0x09, 0, 0, 0, 0], 0),
+            Err("byte 0: field 1 is truncated".to_string())
+        );
+    }
+}
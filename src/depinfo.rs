@@ -0,0 +1,93 @@
+//! Parser for the Makefile-style dependency files written by `rustc --emit=dep-info`.
+
+use std::path::PathBuf;
+
+/// Returns every prerequisite listed in a dep-info file, in order and without duplicates.
+///
+/// rustc writes one rule per output followed by an empty rule for each source file, and
+/// escapes spaces in paths with a backslash. Comment lines (such as `# env-dep:`) are skipped.
+pub(crate) fn parse(contents: &str) -> Vec<PathBuf> {
+    let mut deps: Vec<PathBuf> = Vec::new();
+    // Join continuation lines first so that each rule is on a single line.
+    let joined = contents.replace("\\\n", " ");
+    for line in joined.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let prerequisites = match split_rule(line) {
+            Some(prerequisites) => prerequisites,
+            None => continue,
+        };
+        for dep in split_paths(prerequisites) {
+            let dep = PathBuf::from(dep);
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+    }
+    deps
+}
+
+/// Returns the part of a rule after the unescaped `:` separating targets from prerequisites.
+fn split_rule(line: &str) -> Option<&str> {
+    let bytes = line.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        // Only a colon followed by whitespace separates a rule, so `C:\foo` stays a path.
+        if b == b':' && (i == 0 || bytes[i - 1] != b'\\') {
+            let next = bytes.get(i + 1);
+            if next.is_none() || next == Some(&b' ') || next == Some(&b'\t') {
+                return Some(&line[i + 1..]);
+            }
+        }
+    }
+    None
+}
+
+fn split_paths(prerequisites: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut current = String::new();
+    let mut chars = prerequisites.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                current.push(' ');
+                chars.next();
+            }
+            ' ' | '\t' => {
+                if !current.is_empty() {
+                    paths.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        paths.push(current);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        let contents = "bazel-out/bin/libfoo.rlib: src/lib.rs src/a\\ b.rs \\\n  data/x.txt\n\
+                        \n\
+                        src/lib.rs:\n\
+                        src/a\\ b.rs:\n\
+                        data/x.txt:\n\
+                        \n\
+                        # env-dep:CARGO_PKG_NAME=foo\n";
+        assert_eq!(
+            parse(contents),
+            vec![
+                PathBuf::from("src/lib.rs"),
+                PathBuf::from("src/a b.rs"),
+                PathBuf::from("data/x.txt"),
+            ]
+        );
+    }
+}
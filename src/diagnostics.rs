@@ -0,0 +1,169 @@
+//! rustc's JSON diagnostics: rendering them into the response output, and keeping them in a
+//! `.diagnostics.json` file next to the crate's outputs for IDEs and other tools.
+//!
+//! rustc is run with `--error-format=json --json=diagnostic-rendered-ansi`, so each diagnostic
+//! comes with the text rustc would have printed, in color. Anything on stderr that is not a
+//! diagnostic, such as the message of an internal compiler error, is passed through as is.
+
+use crate::json;
+use crate::json::Value;
+use crate::rustc_args::RustcArgs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Whether the rendered diagnostics keep their colors, like Bazel's `--color`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    Yes,
+    No,
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color::No
+    }
+}
+
+impl std::str::FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yes" => Ok(Color::Yes),
+            "no" => Ok(Color::No),
+            _ => Err(format!("unknown color setting {:?}", s)),
+        }
+    }
+}
+
+/// Asks rustc for JSON diagnostics, unless the request chose an error format itself, in which
+/// case whoever sent it is going to read the output. Returns whether it did.
+pub(crate) fn prepare(args: &RustcArgs, cmd: &mut Command) -> bool {
+    if args.value("--error-format").is_some() {
+        return false;
+    }
+    cmd.arg("--error-format=json");
+    cmd.arg("--json=diagnostic-rendered-ansi");
+    true
+}
+
+/// rustc's stderr with the diagnostics rendered as text, and the diagnostics themselves.
+pub(crate) struct Rendered {
+    pub(crate) output: String,
+    pub(crate) diagnostics: Vec<Value>,
+}
+
+pub(crate) fn render(stderr: &str, color: Color) -> Rendered {
+    let mut rendered = Rendered {
+        output: String::new(),
+        diagnostics: Vec::new(),
+    };
+    for line in crate::split_lines(stderr) {
+        let diagnostic = if line.starts_with('{') {
+            json::parse(line.trim_end()).ok()
+        } else {
+            None
+        };
+        let diagnostic = match diagnostic {
+            Some(diagnostic) => diagnostic,
+            None => {
+                rendered.output.push_str(line);
+                continue;
+            }
+        };
+        // Other messages, like artifact notifications, are not for people to read.
+        let message_type = diagnostic.get("$message_type").and_then(Value::as_str);
+        if message_type.unwrap_or("diagnostic") != "diagnostic" {
+            continue;
+        }
+        if let Some(text) = diagnostic.get("rendered").and_then(Value::as_str) {
+            match color {
+                Color::Yes => rendered.output.push_str(text),
+                Color::No => rendered.output.push_str(&strip_ansi(text)),
+            }
+        }
+        rendered.diagnostics.push(diagnostic);
+    }
+    rendered
+}
+
+/// Removes the ANSI escape sequences rustc colors its output with.
+pub(crate) fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            out.push(c);
+            continue;
+        }
+        // A control sequence is `ESC [`, parameters, and a final letter.
+        if chars.next() == Some('[') {
+            for c in &mut chars {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Writes the diagnostics to `<file stem>.diagnostics.json` in the output directory, or next to
+/// the `-o` output, if the request made in `cwd` has either.
+pub(crate) fn write(cwd: &Path, args: &RustcArgs, diagnostics: Vec<Value>) -> io::Result<()> {
+    let dir = match (args.out_dir(), args.value("-o")) {
+        (Some(out_dir), _) => out_dir,
+        (None, Some(output)) => PathBuf::from(output)
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        (None, None) => return Ok(()),
+    };
+    let stem = match args.file_stem() {
+        Some(stem) => stem,
+        None => return Ok(()),
+    };
+    let path = cwd.join(dir).join(format!("{}.diagnostics.json", stem));
+    let mut text = String::from("[\n");
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        let separator = if i + 1 < diagnostics.len() { "," } else { "" };
+        text.push_str(&format!("{}{}\n", diagnostic, separator));
+    }
+    text.push_str("]\n");
+    std::fs::write(path, text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STDERR: &str = concat!(
+        r#"{"$message_type":"diagnostic","message":"unused variable: `x`","level":"warning","#,
+        r#""rendered":"\u001b[1m\u001b[33mwarning\u001b[0m: unused variable: `x`\n\n"}"#,
+        "\n",
+        r#"{"$message_type":"artifact","artifact":"libfoo.rmeta","emit":"metadata"}"#,
+        "\n",
+        "thread 'rustc' panicked at src/lib.rs:1:1\n",
+    );
+
+    #[test]
+    fn test_render() {
+        let rendered = render(STDERR, Color::No);
+        assert_eq!(
+            rendered.output,
+            "warning: unused variable: `x`\n\nthread 'rustc' panicked at src/lib.rs:1:1\n"
+        );
+        assert_eq!(rendered.diagnostics.len(), 1);
+        assert_eq!(
+            rendered.diagnostics[0].get("level").and_then(Value::as_str),
+            Some("warning")
+        );
+
+        let rendered = render(STDERR, Color::Yes);
+        assert!(rendered
+            .output
+            .starts_with("\u{1b}[1m\u{1b}[33mwarning\u{1b}[0m: unused variable"));
+    }
+}
@@ -0,0 +1,342 @@
+//! The build graph of the crates the worker compiled, from the `--extern` flags of the recorded
+//! invocations, with how long each crate took to compile.
+//!
+//! A crate is connected to a dependency when one of its `--extern` paths is an output of the
+//! dependency. The critical path is the chain of dependencies with the longest total compile
+//! time: however many workers there are, a build of the top crate takes at least that long.
+
+use crate::cache;
+use crate::invocations;
+use crate::invocations::Invocation;
+use crate::json::Value;
+use crate::rustc_args::RustcArgs;
+use crate::unused_deps;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How the graph is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Graphviz.
+    Dot,
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Dot
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown graph format {:?}", s)),
+        }
+    }
+}
+
+struct Node {
+    id: String,
+    invocation: Invocation,
+    /// The Bazel label of the target, if it can be told from the output directory.
+    label: Option<String>,
+    /// The dependencies, by index, with the names the crate knows them by.
+    deps: Vec<(usize, String)>,
+    critical: bool,
+    /// The dependency the critical path continues with, if it goes through this crate.
+    critical_dep: Option<usize>,
+}
+
+impl Node {
+    fn duration(&self) -> Duration {
+        self.invocation.duration.unwrap_or_default()
+    }
+}
+
+struct Graph {
+    nodes: Vec<Node>,
+    /// The total compile time of the critical path.
+    critical: Duration,
+}
+
+impl Graph {
+    fn new(invocations: Vec<Invocation>) -> Self {
+        let mut nodes: Vec<Node> = invocations
+            .into_iter()
+            .map(|invocation| {
+                let args = RustcArgs::new(&invocation.args);
+                let label = args
+                    .out_dir()
+                    .as_deref()
+                    .and_then(unused_deps::package_dir)
+                    .map(|package| unused_deps::label(&package, &invocation.crate_name));
+                Node {
+                    id: invocation.key(),
+                    invocation,
+                    label,
+                    deps: Vec::new(),
+                    critical: false,
+                    critical_dep: None,
+                }
+            })
+            .collect();
+        let mut by_output: HashMap<PathBuf, usize> = HashMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for output in node.invocation.outputs() {
+                let output = node.invocation.resolve(&output.to_string_lossy());
+                by_output.insert(output, index);
+            }
+        }
+        for node in &mut nodes {
+            let args = RustcArgs::new(&node.invocation.args);
+            node.deps = unused_deps::externs(&args)
+                .into_iter()
+                .filter_map(|(name, path)| {
+                    let index = by_output.get(&node.invocation.resolve(path?))?;
+                    Some((*index, name.to_string()))
+                })
+                .collect();
+        }
+        let mut graph = Graph {
+            nodes,
+            critical: Duration::default(),
+        };
+        graph.mark_critical_path();
+        graph
+    }
+
+    /// The longest total compile time of a chain of dependencies starting at each node, and
+    /// the dependency that chain continues with.
+    fn longest_paths(&self) -> Vec<(Duration, Option<usize>)> {
+        fn visit(
+            graph: &Graph,
+            index: usize,
+            paths: &mut Vec<Option<(Duration, Option<usize>)>>,
+        ) -> Duration {
+            if let Some((length, _)) = paths[index] {
+                return length;
+            }
+            // Guards against cycles, which a build graph can't have but stale invocations can.
+            paths[index] = Some((Duration::default(), None));
+            let mut longest = (Duration::default(), None);
+            for (dep, _) in &graph.nodes[index].deps {
+                let length = visit(graph, *dep, paths);
+                if longest.1.is_none() || length > longest.0 {
+                    longest = (length, Some(*dep));
+                }
+            }
+            let length = graph.nodes[index].duration() + longest.0;
+            paths[index] = Some((length, longest.1));
+            length
+        }
+
+        let mut paths = vec![None; self.nodes.len()];
+        for index in 0..self.nodes.len() {
+            visit(self, index, &mut paths);
+        }
+        paths.into_iter().map(Option::unwrap_or_default).collect()
+    }
+
+    fn mark_critical_path(&mut self) {
+        let paths = self.longest_paths();
+        let mut next = (0..paths.len()).max_by_key(|index| paths[*index].0);
+        if let Some(start) = next {
+            self.critical = paths[start].0;
+        }
+        while let Some(index) = next {
+            if self.nodes[index].critical {
+                break;
+            }
+            self.nodes[index].critical = true;
+            self.nodes[index].critical_dep = paths[index].1;
+            next = paths[index].1;
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph crates {\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let mut label = node.invocation.crate_name.clone();
+            if let Some(target) = &node.label {
+                label.push_str(&format!("\\n{}", target));
+            }
+            if let Some(duration) = node.invocation.duration {
+                label.push_str(&format!("\\n{:.2}s", duration.as_secs_f64()));
+            }
+            let color = if node.critical { ", color=red" } else { "" };
+            dot.push_str(&format!(
+                "    {:?} [label=\"{}\"{}];\n",
+                node.id, label, color
+            ));
+        }
+        for node in &self.nodes {
+            for (dep, _) in &node.deps {
+                let color = if node.critical_dep == Some(*dep) {
+                    " [color=red]"
+                } else {
+                    ""
+                };
+                let dep = &self.nodes[*dep];
+                dot.push_str(&format!("    {:?} -> {:?}{};\n", node.id, dep.id, color));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn to_json(&self) -> Value {
+        let nodes: Vec<Value> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let args = RustcArgs::new(&node.invocation.args);
+                // Only the outputs the crate actually has, of all the ones it could have.
+                let outputs: Vec<String> = node
+                    .invocation
+                    .outputs()
+                    .iter()
+                    .map(|output| output.to_string_lossy().into_owned())
+                    .filter(|output| node.invocation.resolve(output).exists())
+                    .collect();
+                Value::object()
+                    .with("id", node.id.as_str())
+                    .with("crate", node.invocation.crate_name.as_str())
+                    .with("label", node.label.clone())
+                    .with("crate_types", args.values("--crate-type"))
+                    .with("outputs", outputs)
+                    .with(
+                        "duration_ms",
+                        node.invocation
+                            .duration
+                            .map(|duration| duration.as_millis() as u64),
+                    )
+                    .with("critical", node.critical)
+            })
+            .collect();
+        let edges: Vec<Value> = self
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                node.deps.iter().map(move |(dep, name)| {
+                    Value::object()
+                        .with("from", node.id.as_str())
+                        .with("to", self.nodes[*dep].id.as_str())
+                        .with("name", name.as_str())
+                        .with("critical", node.critical_dep == Some(*dep))
+                })
+            })
+            .collect();
+        Value::object()
+            .with("nodes", nodes)
+            .with("edges", edges)
+            .with("critical_path_ms", self.critical.as_millis() as u64)
+    }
+}
+
+/// Writes the graph of the crates compiled with the given caches, or every cache when none are
+/// given.
+pub fn export<W: io::Write>(caches: &[PathBuf], format: Format, out: &mut W) -> io::Result<()> {
+    let caches = if caches.is_empty() {
+        cache::discover()?
+    } else {
+        caches.to_vec()
+    };
+    let mut all = Vec::new();
+    for cache in &caches {
+        all.extend(invocations::load(cache)?);
+    }
+    let graph = Graph::new(all);
+    match format {
+        Format::Dot => write!(out, "{}", graph.to_dot()),
+        Format::Json => writeln!(out, "{}", graph.to_json().pretty()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn invocation(args: &[&str], duration_ms: u64) -> Invocation {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Invocation {
+            time: 0,
+            crate_name: RustcArgs::new(&args).crate_name().unwrap().to_string(),
+            cwd: PathBuf::from("/execroot"),
+            args,
+            env: Vec::new(),
+            duration: Some(Duration::from_millis(duration_ms)),
+        }
+    }
+
+    #[test]
+    fn test_graph() {
+        let graph = Graph::new(vec![
+            invocation(
+                &[
+                    "--crate-name=app",
+                    "--extern=fast=bazel-out/k8-fastbuild/bin/fast/libfast.rlib",
+                    "--extern=slow=bazel-out/k8-fastbuild/bin/slow/libslow.rmeta",
+                    "--extern=missing=bazel-out/k8-fastbuild/bin/missing/libmissing.rlib",
+                    "--out-dir=bazel-out/k8-fastbuild/bin/app",
+                ],
+                100,
+            ),
+            invocation(
+                &[
+                    "--crate-name=fast",
+                    "--out-dir=bazel-out/k8-fastbuild/bin/fast",
+                ],
+                10,
+            ),
+            invocation(
+                &[
+                    "--crate-name=slow",
+                    "--out-dir=bazel-out/k8-fastbuild/bin/slow",
+                ],
+                1000,
+            ),
+        ]);
+        assert_eq!(
+            graph.nodes[0].deps,
+            [(1, "fast".to_string()), (2, "slow".to_string())]
+        );
+        assert_eq!(graph.nodes[0].label.as_deref(), Some("//app:app"));
+        let critical: Vec<bool> = graph.nodes.iter().map(|node| node.critical).collect();
+        assert_eq!(critical, [true, false, true]);
+        assert_eq!(graph.critical, Duration::from_millis(1100));
+
+        let dot = graph.to_dot();
+        let (app, slow) = (&graph.nodes[0].id, &graph.nodes[2].id);
+        assert!(
+            dot.contains(&format!(
+                "    {:?} [label=\"app\\n//app:app\\n0.10s\", color=red];\n",
+                app
+            )),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains(&format!("    {:?} -> {:?} [color=red];\n", app, slow)),
+            "{}",
+            dot
+        );
+        let json = graph.to_json();
+        assert_eq!(
+            json.get("critical_path_ms").and_then(Value::as_i64),
+            Some(1100)
+        );
+        assert_eq!(
+            json.get("edges")
+                .and_then(Value::as_array)
+                .map(<[Value]>::len),
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Checks that rustc only read the files Bazel declared as inputs of a request.
+
+use crate::depinfo;
+use crate::lock::PrivateDir;
+use crate::rustc_args::RustcArgs;
+use crate::worker_protocol::Input;
+use std::io;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// What to do when rustc reads a file that is not a declared input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hermeticity {
+    Off,
+    /// Append a warning to the response output.
+    Warn,
+    /// Append an error to the response output and fail the request.
+    Error,
+}
+
+impl Default for Hermeticity {
+    fn default() -> Self {
+        Hermeticity::Off
+    }
+}
+
+impl std::str::FromStr for Hermeticity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Hermeticity::Off),
+            "warn" => Ok(Hermeticity::Warn),
+            "error" => Ok(Hermeticity::Error),
+            _ => Err(format!("unknown hermeticity mode {:?}", s)),
+        }
+    }
+}
+
+/// Where rustc writes the dep-info for a request.
+pub(crate) enum DepInfo {
+    /// The request asked for dep-info itself, so it must be left in place.
+    Requested(PathBuf),
+    /// The worker asked for dep-info, in a directory of its own that is removed afterwards.
+    Scratch { _dir: PrivateDir, path: PathBuf },
+}
+
+impl DepInfo {
+    /// Makes sure rustc writes dep-info for the request, without moving a dep-info file the
+    /// request already asked for.
+    pub(crate) fn prepare(args: &RustcArgs, cmd: &mut Command) -> io::Result<Self> {
+        let emit = args.emit();
+        for &(kind, path) in &emit {
+            if kind != "dep-info" {
+                continue;
+            }
+            if let Some(path) = path {
+                return Ok(DepInfo::Requested(PathBuf::from(path)));
+            }
+            if let Some(path) = default_path(args) {
+                return Ok(DepInfo::Requested(path));
+            }
+        }
+        // Not a predictable path in the shared temporary directory, where another user could
+        // have planted a symlink for rustc to write through.
+        let dir = PrivateDir::create()?;
+        let path = dir.path().join("dep-info.d");
+        // Any `--emit` replaces rustc's default of `link`, so keep it when the request relied
+        // on the default.
+        let mut emit_arg = std::ffi::OsString::from(if emit.is_empty() {
+            "--emit=link,dep-info="
+        } else {
+            "--emit=dep-info="
+        });
+        emit_arg.push(&path);
+        cmd.arg(emit_arg);
+        Ok(DepInfo::Scratch { _dir: dir, path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            DepInfo::Requested(path) | DepInfo::Scratch { path, .. } => path,
+        }
+    }
+}
+
+/// The location rustc uses for dep-info when `--emit` does not give one.
+fn default_path(args: &RustcArgs) -> Option<PathBuf> {
+    if let Some(output) = args.value("-o") {
+        return Some(Path::new(output).with_extension("d"));
+    }
+    let mut path = args.out_dir().unwrap_or_default();
+    path.push(format!(
+        "{}{}.d",
+        args.crate_name()?,
+        args.codegen("extra-filename").unwrap_or("")
+    ));
+    Some(path)
+}
+
+/// Returns the files listed in the dep-info that are not among the declared inputs of a request
+/// made in `cwd`.
+pub(crate) fn undeclared_reads(
+    cwd: &Path,
+    dep_info: &Path,
+    inputs: &[Input],
+) -> io::Result<Vec<PathBuf>> {
+    let declared: Vec<PathBuf> = inputs
+        .iter()
+        .map(|input| normalize(cwd, Path::new(input.get_path())))
+        .collect();
+    let contents = std::fs::read_to_string(cwd.join(dep_info))?;
+    Ok(depinfo::parse(&contents)
+        .into_iter()
+        .filter(|dep| !declared.contains(&normalize(cwd, dep)))
+        .collect())
+}
+
+/// Makes a path relative to the execution root where possible and drops `.` components, so
+/// that paths written by rustc and by Bazel compare equal. Symlinks are deliberately not
+/// resolved since sandboxes are built out of them.
+fn normalize(cwd: &Path, path: &Path) -> PathBuf {
+    let path = path.strip_prefix(cwd).unwrap_or(path);
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Renders the report appended to the response output.
+pub(crate) fn report(mode: Hermeticity, undeclared: &[PathBuf]) -> String {
+    let level = match mode {
+        Hermeticity::Error => "error",
+        _ => "warning",
+    };
+    let mut report = format!(
+        "{}: rustc read {} file(s) not declared as inputs of this action:\n",
+        level,
+        undeclared.len()
+    );
+    for path in undeclared {
+        report.push_str(&format!("  {}\n", path.display()));
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+    use std::path::Path;
+
+    #[test]
+    fn test_normalize() {
+        let cwd = Path::new("/execroot/__main__");
+        assert_eq!(
+            normalize(cwd, Path::new("/execroot/__main__/./src/lib.rs")),
+            Path::new("src/lib.rs")
+        );
+        assert_eq!(
+            normalize(cwd, Path::new("./src/lib.rs")),
+            Path::new("src/lib.rs")
+        );
+        assert_eq!(
+            normalize(cwd, Path::new("/usr/include/x")),
+            Path::new("/usr/include/x")
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! A history of how long each crate took to compile, kept in `history.jsonl` in each cache
+//! directory, to measure what incremental compilation actually saves.
+//!
+//! Every compilation adds a line:
+//!
+//! ```text
+//! {"time":...,"crate":"foo","kind":"warm","duration_ms":...,"exit_code":0}
+//! ```
+
+use crate::cache;
+use crate::json;
+use crate::json::Value;
+use crate::manifest;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const FILE_NAME: &str = "history.jsonl";
+
+/// Once the history is this big, the older half of it is dropped.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// The state of the incremental cache a crate was compiled with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    /// rustc had a session from an earlier compilation to start from.
+    Warm,
+    /// Incremental compilation was on, but there was nothing to start from.
+    Cold,
+    /// Incremental compilation was off.
+    Off,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Warm, Kind::Cold, Kind::Off];
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Warm => "warm",
+            Kind::Cold => "cold",
+            Kind::Off => "off",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Kind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+}
+
+/// Records a compilation of a crate.
+pub(crate) fn record(
+    cache: &Path,
+    crate_name: &str,
+    kind: Kind,
+    duration: Duration,
+    exit_code: i32,
+) -> io::Result<()> {
+    let path = cache.join(FILE_NAME);
+    let entry = Value::object()
+        .with("time", manifest::now())
+        .with("crate", crate_name)
+        .with("kind", kind.name())
+        .with("duration_ms", duration.as_millis() as u64)
+        .with("exit_code", exit_code);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    // A single write keeps lines from concurrent workers from interleaving.
+    file.write_all(format!("{}\n", entry).as_bytes())?;
+    if file.metadata()?.len() > MAX_SIZE {
+        trim(&path)?;
+    }
+    Ok(())
+}
+
+/// Drops the older half of the history, or of another log. Like the manifest, an entry a
+/// concurrent worker adds meanwhile can occasionally be lost.
+pub(crate) fn trim(path: &Path) -> io::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut kept = lines[lines.len() / 2..].join("\n");
+    kept.push('\n');
+    let tmp = path.with_extension(format!("jsonl.{}", std::process::id()));
+    std::fs::write(&tmp, kept)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// The successful compile durations of each crate, by kind.
+fn load(cache: &Path) -> io::Result<BTreeMap<String, Vec<(Kind, Duration)>>> {
+    let mut crates: BTreeMap<String, Vec<(Kind, Duration)>> = BTreeMap::new();
+    let contents = match std::fs::read_to_string(cache.join(FILE_NAME)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(crates),
+        Err(e) => return Err(e),
+    };
+    for line in contents.lines() {
+        // Skip lines cut short by a crash rather than failing on them.
+        let entry = match json::parse(line) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let crate_name = entry.get("crate").and_then(Value::as_str);
+        let kind = entry
+            .get("kind")
+            .and_then(Value::as_str)
+            .and_then(Kind::from_name);
+        let duration = entry.get("duration_ms").and_then(Value::as_i64);
+        let exit_code = entry.get("exit_code").and_then(Value::as_i64);
+        if let (Some(crate_name), Some(kind), Some(duration), Some(0)) =
+            (crate_name, kind, duration, exit_code)
+        {
+            crates
+                .entry(crate_name.to_string())
+                .or_default()
+                .push((kind, Duration::from_millis(duration as u64)));
+        }
+    }
+    Ok(crates)
+}
+
+fn median(mut durations: Vec<Duration>) -> Option<Duration> {
+    durations.sort();
+    durations.get(durations.len() / 2).copied()
+}
+
+/// The median duration and number of compilations of a kind, as a table cell.
+fn describe(durations: &[(Kind, Duration)], kind: Kind) -> (Option<Duration>, String) {
+    let durations: Vec<Duration> = durations
+        .iter()
+        .filter(|(k, _)| *k == kind)
+        .map(|(_, duration)| *duration)
+        .collect();
+    let count = durations.len();
+    match median(durations) {
+        Some(median) => (
+            Some(median),
+            format!("{:.2}s ({})", median.as_secs_f64(), count),
+        ),
+        None => (None, "-".to_string()),
+    }
+}
+
+fn speedup(baseline: Option<Duration>, warm: Option<Duration>) -> String {
+    match (baseline, warm) {
+        (Some(baseline), Some(warm)) if warm > Duration::from_millis(0) => {
+            format!("{:.1}x", baseline.as_secs_f64() / warm.as_secs_f64())
+        }
+        _ => "-".to_string(),
+    }
+}
+
+/// Prints the median compile time of each crate with a warm cache, a cold cache and without
+/// incremental compilation, and how much faster the warm compilations were. Covers every cache
+/// when none are given.
+pub fn summarize<W: io::Write>(caches: &[PathBuf], out: &mut W) -> io::Result<()> {
+    let caches = if caches.is_empty() {
+        cache::discover()?
+    } else {
+        caches.to_vec()
+    };
+    for cache in caches {
+        let crates = load(&cache)?;
+        if crates.is_empty() {
+            continue;
+        }
+        writeln!(out, "{}", cache.display())?;
+        let width = crates.keys().map(String::len).max().unwrap_or(0).max(5);
+        writeln!(
+            out,
+            "  {:width$}  {:>12}  {:>12}  {:>12}  {:>9}  {:>9}",
+            "crate",
+            "warm",
+            "cold",
+            "off",
+            "cold/warm",
+            "off/warm",
+            width = width
+        )?;
+        for (crate_name, durations) in &crates {
+            let (warm, warm_cell) = describe(durations, Kind::Warm);
+            let (cold, cold_cell) = describe(durations, Kind::Cold);
+            let (off, off_cell) = describe(durations, Kind::Off);
+            writeln!(
+                out,
+                "  {:width$}  {:>12}  {:>12}  {:>12}  {:>9}  {:>9}",
+                crate_name,
+                warm_cell,
+                cold_cell,
+                off_cell,
+                speedup(cold, warm),
+                speedup(off, warm),
+                width = width
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let cache =
+            std::env::temp_dir().join(format!("rustc-worker-history-test-{}", std::process::id()));
+        std::fs::create_dir_all(&cache).unwrap();
+        let ms = Duration::from_millis;
+        record(&cache, "foo", Kind::Cold, ms(3000), 0).unwrap();
+        record(&cache, "foo", Kind::Warm, ms(500), 0).unwrap();
+        record(&cache, "foo", Kind::Warm, ms(600), 0).unwrap();
+        record(&cache, "foo", Kind::Warm, ms(9000), 1).unwrap();
+        record(&cache, "foo", Kind::Off, ms(2400), 0).unwrap();
+        record(&cache, "bar", Kind::Cold, ms(100), 0).unwrap();
+
+        let mut out = Vec::new();
+        summarize(std::slice::from_ref(&cache), &mut out).unwrap();
+        std::fs::remove_dir_all(&cache).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{}\n\
+                 \x20 crate          warm          cold           off  cold/warm   off/warm\n\
+                 \x20 bar               -     0.10s (1)             -          -          -\n\
+                 \x20 foo       0.60s (2)     3.00s (1)     2.40s (1)       5.0x       4.0x\n",
+                cache.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! The last rustc invocation of every crate, kept in the `invocations` directory of each cache as
+//! `<crate>-<hash>.json`, so the crate graph and command lines of a build can be used after the
+//! build:
+//!
+//! ```text
+//! {"time":...,"crate":"foo","cwd":"...","args":[...],"env":{"CARGO_PKG_NAME":"foo",...},
+//!  "duration_ms":...}
+//! ```
+//!
+//! Crates with the same name in different packages, or a library and its tests, are told apart
+//! by a hash of their outputs.
+
+use crate::json;
+use crate::json::Value;
+use crate::manifest;
+use crate::rustc_args::RustcArgs;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The directory in the cache the invocations are kept in.
+pub(crate) const DIR: &str = "invocations";
+
+/// Whether an environment variable is one rustc or the crate can see through `env!`, as opposed
+/// to whatever else the worker was started with.
+fn is_crate_env(name: &str) -> bool {
+    name.starts_with("CARGO_") || name == "OUT_DIR"
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Invocation {
+    /// Seconds since the epoch.
+    pub(crate) time: u64,
+    pub(crate) crate_name: String,
+    /// The directory rustc ran in.
+    pub(crate) cwd: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    /// How long rustc took, unknown for invocations recorded by older workers.
+    pub(crate) duration: Option<Duration>,
+}
+
+impl Invocation {
+    /// Tells apart the invocations of crates with the same name, as the name of their file.
+    pub(crate) fn key(&self) -> String {
+        let args = RustcArgs::new(&self.args);
+        let mut hasher = DefaultHasher::new();
+        args.out_dir().hash(&mut hasher);
+        args.value("-o").hash(&mut hasher);
+        args.codegen("extra-filename").hash(&mut hasher);
+        args.values("--crate-type").hash(&mut hasher);
+        args.has("--test").hash(&mut hasher);
+        format!("{}-{:016x}", self.crate_name, hasher.finish())
+    }
+
+    fn to_json(&self) -> Value {
+        Value::object()
+            .with("time", self.time)
+            .with("crate", self.crate_name.as_str())
+            .with("cwd", self.cwd.to_string_lossy().into_owned())
+            .with("args", self.args.clone())
+            .with(
+                "env",
+                Value::Object(
+                    self.env
+                        .iter()
+                        .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                        .collect(),
+                ),
+            )
+            .with(
+                "duration_ms",
+                self.duration.map(|duration| duration.as_millis() as u64),
+            )
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let strings = |key| -> Option<Vec<String>> {
+            value
+                .get(key)?
+                .as_array()?
+                .iter()
+                .map(|arg| arg.as_str().map(String::from))
+                .collect()
+        };
+        let env = match value.get("env") {
+            Some(Value::Object(entries)) => entries
+                .iter()
+                .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(Invocation {
+            time: value.get("time")?.as_i64()? as u64,
+            crate_name: value.get("crate")?.as_str()?.to_string(),
+            cwd: value.get("cwd")?.as_str()?.into(),
+            args: strings("args")?,
+            env,
+            duration: value
+                .get("duration_ms")
+                .and_then(Value::as_i64)
+                .map(|ms| Duration::from_millis(ms as u64)),
+        })
+    }
+
+    /// The path of a file rustc was given, as seen from outside of its working directory.
+    pub(crate) fn resolve(&self, path: &str) -> PathBuf {
+        self.cwd.join(path)
+    }
+
+    /// The files the compilation may write, as rustc was given them: the rlib, rmeta, shared
+    /// libraries and executables in the output directory, and explicit `--emit` paths.
+    pub(crate) fn outputs(&self) -> Vec<PathBuf> {
+        let args = RustcArgs::new(&self.args);
+        let mut outputs: Vec<PathBuf> = args
+            .emit()
+            .into_iter()
+            .filter_map(|(_, path)| path.map(PathBuf::from))
+            .collect();
+        if let Some(output) = args.value("-o") {
+            outputs.push(output.into());
+        }
+        if let Some(out_dir) = args.out_dir() {
+            let stem = format!(
+                "{}{}",
+                self.crate_name,
+                args.codegen("extra-filename").unwrap_or("")
+            );
+            for extension in &["rlib", "rmeta", "so", "dylib", "dll"] {
+                outputs.push(out_dir.join(format!("lib{}.{}", stem, extension)));
+            }
+            outputs.push(out_dir.join(&stem));
+            outputs.push(out_dir.join(format!("{}.exe", stem)));
+        }
+        outputs
+    }
+}
+
+/// Records the invocation of a crate in `cwd` and how long it took, replacing the previous one.
+pub(crate) fn record(
+    cache: &Path,
+    cwd: &Path,
+    args: &[String],
+    duration: Duration,
+) -> io::Result<()> {
+    let crate_name = match RustcArgs::new(args).crate_name() {
+        Some(crate_name) => crate_name.to_string(),
+        None => return Ok(()),
+    };
+    let invocation = Invocation {
+        time: manifest::now(),
+        crate_name,
+        cwd: cwd.to_owned(),
+        args: args.to_vec(),
+        env: std::env::vars()
+            .filter(|(name, _)| is_crate_env(name))
+            .collect(),
+        duration: Some(duration),
+    };
+    let dir = cache.join(DIR);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.json", invocation.key()));
+    // Written to a temporary file first, so readers never see half of it.
+    let tmp = path.with_extension(format!("json.{}", std::process::id()));
+    std::fs::write(&tmp, invocation.to_json().to_string() + "\n")?;
+    std::fs::rename(&tmp, &path)
+}
+
+/// Every recorded invocation in the cache, sorted by crate name.
+pub(crate) fn load(cache: &Path) -> io::Result<Vec<Invocation>> {
+    let entries = match std::fs::read_dir(cache.join(DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut invocations = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        // Skip files that disappeared or can't be read rather than failing on them.
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        if let Some(invocation) = json::parse(&contents)
+            .ok()
+            .as_ref()
+            .and_then(Invocation::from_json)
+        {
+            invocations.push(invocation);
+        }
+    }
+    invocations.sort_by(|a, b| (&a.crate_name, a.time).cmp(&(&b.crate_name, b.time)));
+    Ok(invocations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let cache = std::env::temp_dir().join(format!(
+            "rustc-worker-invocations-test-{}",
+            std::process::id()
+        ));
+        let args = |crate_type: &str| -> Vec<String> {
+            [
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                crate_type,
+                "--out-dir=out",
+                "src/lib.rs",
+            ]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect()
+        };
+        let execroot = Path::new("/execroot");
+        let duration = Duration::from_millis(5);
+        record(&cache, execroot, &args("rlib"), duration).unwrap();
+        record(&cache, execroot, &args("rlib"), duration).unwrap();
+        record(&cache, execroot, &args("bin"), duration).unwrap();
+        record(&cache, execroot, &args("bin")[2..], duration).unwrap();
+
+        let invocations = load(&cache).unwrap();
+        std::fs::remove_dir_all(&cache).unwrap();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].crate_name, "foo");
+        assert_eq!(invocations[0].cwd, execroot);
+        assert_eq!(invocations[0].duration, Some(Duration::from_millis(5)));
+        assert!(invocations
+            .iter()
+            .any(|invocation| invocation.args == args("rlib")));
+        assert!(invocations[0]
+            .outputs()
+            .contains(&PathBuf::from("out/libfoo.rlib")));
+    }
+}
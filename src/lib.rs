@@ -9,13 +9,220 @@ use std::io;
 use std::io::BufRead;
 use std::path::PathBuf;
 
+pub mod cache;
+pub mod check;
+mod child;
+mod client;
+mod decode;
+mod depinfo;
+mod diagnostics;
+pub mod graph;
+mod hermeticity;
+pub mod history;
+mod invocations;
+mod json;
+mod limits;
+mod locations;
+mod lock;
+mod manifest;
+mod protocol_json;
+mod record;
+pub mod rust_project;
+mod rustc_args;
+mod sample;
+mod sarif;
+mod stats;
+pub mod timings;
+mod trace;
+mod unused_deps;
+mod verify;
 mod worker_protocol;
-use worker_protocol::WorkRequest;
-use worker_protocol::WorkResponse;
+pub use client::WorkerClient;
+pub use decode::decode;
+pub use decode::Stream;
+pub use diagnostics::Color;
+pub use hermeticity::Hermeticity;
+pub use record::replay;
+use rustc_args::RustcArgs;
+pub use sample::Sample;
+pub use verify::Verify;
+pub use worker_protocol::Input;
+pub use worker_protocol::WorkRequest;
+pub use worker_protocol::WorkResponse;
+
+/// Optional behavior of the worker, set from command line flags.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Whether to check the files rustc read against the declared inputs of each request.
+    pub hermeticity: Hermeticity,
+    /// Where to write the per-target reports of unused `--extern` dependencies, if anywhere.
+    pub unused_deps_report: Option<PathBuf>,
+    /// A log to append every request and response to, for replaying them later.
+    pub record: Option<PathBuf>,
+    /// Bazel's output base, which identifies the checkout in the cache key. Detected from the
+    /// working directory when not given.
+    pub output_base: Option<PathBuf>,
+    /// The directory to create the incremental cache in, instead of the temporary directory.
+    pub cache_root: Option<PathBuf>,
+    /// How long to wait for another worker compiling the same crate before compiling it with a
+    /// private incremental directory instead.
+    pub lock_timeout: std::time::Duration,
+    /// How long rustc may run for a request before it is killed, if there is a limit.
+    pub timeout: Option<std::time::Duration>,
+    /// Timeouts for particular crates, by crate name, overriding `timeout`.
+    pub crate_timeouts: Vec<(String, std::time::Duration)>,
+    /// How much memory rustc may use, in bytes.
+    pub memory_limit: Option<u64>,
+    /// How much CPU time rustc may use.
+    pub cpu_limit: Option<std::time::Duration>,
+    /// A file to append the resource usage of every request to.
+    pub stats: Option<PathBuf>,
+    /// A Chrome trace to append every request to.
+    pub trace: Option<PathBuf>,
+    /// The fraction of requests to compile without the shared incremental cache, to measure
+    /// what it saves.
+    pub sample_rate: f64,
+    /// How to compile the sampled requests.
+    pub sample: Sample,
+    /// Whether to compare incremental compilations with clean ones, and what to do when they
+    /// differ.
+    pub verify: Verify,
+    /// The fraction of requests to verify, none unless given.
+    pub verify_rate: f64,
+    /// Where to save the outputs of compilations that failed verification. A directory in the
+    /// temporary directory when not given.
+    pub verify_bundles: Option<PathBuf>,
+    /// Whether to have rustc write JSON diagnostics, render them into the response output and
+    /// save them next to the crate's outputs.
+    pub json_diagnostics: bool,
+    /// Whether the rendered diagnostics keep their colors.
+    pub color: Color,
+    /// The workspace to rewrite the file locations in diagnostics relative to, if any.
+    pub workspace_root: Option<PathBuf>,
+    /// Where to write a SARIF report of the diagnostics of each crate, if anywhere.
+    pub sarif: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            hermeticity: Hermeticity::default(),
+            unused_deps_report: None,
+            record: None,
+            output_base: None,
+            cache_root: None,
+            lock_timeout: std::time::Duration::from_secs(10),
+            timeout: None,
+            crate_timeouts: Vec::new(),
+            memory_limit: None,
+            cpu_limit: None,
+            stats: None,
+            trace: None,
+            sample_rate: 0.0,
+            sample: Sample::default(),
+            verify: Verify::default(),
+            verify_rate: 0.0,
+            verify_bundles: None,
+            json_diagnostics: false,
+            color: Color::default(),
+            workspace_root: None,
+            sarif: None,
+        }
+    }
+}
+
+/// Finds the output base from the directory Bazel runs the worker in, which is the execroot
+/// (`<output_base>/execroot/<workspace>`) or, for sandboxed workers,
+/// `<output_base>/bazel-workers/...`. Outside of Bazel, the directory itself is used.
+fn detect_output_base(cwd: &std::path::Path) -> PathBuf {
+    let mut output_base = PathBuf::new();
+    for component in cwd.components() {
+        if let std::path::Component::Normal(name) = component {
+            if name == "execroot" || name == "bazel-workers" {
+                return output_base;
+            }
+        }
+        output_base.push(component);
+    }
+    cwd.to_owned()
+}
+
+/// The exit code to report for a finished rustc, along with an explanation when rustc did not
+/// exit by itself.
+fn exit_code(status: std::process::ExitStatus) -> (i32, Option<String>) {
+    if let Some(code) = status.code() {
+        return (code, None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            // Same convention as shells use for processes killed by a signal.
+            return (
+                128 + signal,
+                Some(format!(
+                    "rustc-worker: rustc was killed by signal {}\n",
+                    signal
+                )),
+            );
+        }
+    }
+    (
+        1,
+        Some(format!("rustc-worker: rustc exited with {}\n", status)),
+    )
+}
+
+/// The exit code to exit with after running rustc directly.
+pub fn process_exit_code(status: std::process::ExitStatus) -> i32 {
+    exit_code(status).0
+}
+
+impl Options {
+    fn timeout(&self, crate_name: Option<&str>) -> Option<std::time::Duration> {
+        self.crate_timeouts
+            .iter()
+            .rev()
+            .find(|(name, _)| Some(name.as_str()) == crate_name)
+            .map(|(_, timeout)| *timeout)
+            .or(self.timeout)
+    }
+}
+
+/// The response to a request the worker could not compile.
+fn failure(request_id: i32, output: String) -> WorkResponse {
+    WorkResponse {
+        request_id,
+        exit_code: 1,
+        output,
+        ..Default::default()
+    }
+}
+
+/// What rustc did for a request.
+struct Compiled {
+    finished: child::Finished,
+    /// rustc's stderr, with JSON diagnostics rendered.
+    stderr: String,
+    diagnostics: Option<Vec<json::Value>>,
+    cgroup: Option<limits::Leaf>,
+    dep_info: Option<hermeticity::DepInfo>,
+}
 
 pub struct Worker {
     program_path: PathBuf,
+    rustc: PathBuf,
+    compilation_mode: String,
     incremental_dir: std::path::PathBuf,
+    /// The directory Bazel runs the worker in, and rustc in turn.
+    cwd: PathBuf,
+    limits: limits::Limits,
+    stats: Option<stats::Sink>,
+    trace: Option<trace::Trace>,
+    locations: Option<locations::Locations>,
+    /// How rustc reports unused dependencies, when they are reported.
+    unused_deps: Option<unused_deps::Lint>,
+    options: Options,
 }
 
 impl Worker {
@@ -23,39 +230,461 @@ impl Worker {
         program_path: PathBuf,
         rustc: PathBuf,
         compilation_mode: C,
+    ) -> io::Result<Self> {
+        Self::with_options(program_path, rustc, compilation_mode, Options::default())
+    }
+
+    pub fn with_options<C: Into<String>>(
+        program_path: PathBuf,
+        rustc: PathBuf,
+        compilation_mode: C,
+        options: Options,
+    ) -> io::Result<Self> {
+        let cwd = std::env::current_dir()?;
+        Self::in_dir(program_path, rustc, compilation_mode, options, cwd)
+    }
+
+    /// A worker for requests made in `cwd` rather than the current directory.
+    pub(crate) fn in_dir<C: Into<String>>(
+        program_path: PathBuf,
+        rustc: PathBuf,
+        compilation_mode: C,
+        options: Options,
+        cwd: PathBuf,
     ) -> io::Result<Self> {
         // The incremental cache directory includes the rustc wrapper's hash to discriminate
-        // between multiple workspaces having the same name (usually __main__).
-        let mut cache_path = std::env::temp_dir();
+        // between multiple workspaces having the same name (usually __main__), and the output
+        // base, which Bazel keeps separate for each checkout of the same workspace.
+        let compilation_mode = compilation_mode.into();
+        let output_base = match &options.output_base {
+            Some(output_base) => output_base.clone(),
+            None => detect_output_base(&cwd),
+        };
         let mut hasher = DefaultHasher::new();
         rustc.hash(&mut hasher);
+        output_base.hash(&mut hasher);
 
-        cache_path.push(format!(
-            "rustc-worker-{}-{}",
-            hasher.finish(),
-            compilation_mode.into()
-        ));
-        std::fs::create_dir_all(&cache_path)?;
+        let cache_name = format!("rustc-worker-{}-{}", hasher.finish(), compilation_mode);
+        let cache_path = match &options.cache_root {
+            Some(root) => cache::create_in(root, &cache_name)?,
+            None => cache::create(&cache_name)?,
+        };
+        manifest::init(&cache_path, &rustc, &compilation_mode, &cwd, &output_base)?;
+        let stats = match &options.stats {
+            Some(path) => Some(stats::Sink::open(path)?),
+            None => None,
+        };
+        let trace = match &options.trace {
+            Some(path) => Some(trace::Trace::open(path, &compilation_mode)?),
+            None => None,
+        };
+        let locations = options.workspace_root.as_ref().map(|workspace_root| {
+            locations::Locations::new(workspace_root.clone(), cwd.clone(), output_base.clone())
+        });
+        let unused_deps = options.unused_deps_report.as_ref().map(|_| {
+            let version = manifest::rustc_version(&rustc).unwrap_or_default();
+            unused_deps::Lint::new(&version)
+        });
         Ok(Worker {
             program_path,
+            rustc,
+            compilation_mode,
             incremental_dir: cache_path,
+            cwd,
+            limits: limits::Limits::new(options.memory_limit, options.cpu_limit),
+            stats,
+            trace,
+            locations,
+            unused_deps,
+            options,
         })
     }
 
-    fn handle_request(&self, request: WorkRequest) -> ProtobufResult<WorkResponse> {
-        let mut incremental_arg = std::ffi::OsString::from("incremental=");
-        incremental_arg.push(&self.incremental_dir);
-        let mut cmd = std::process::Command::new(&self.program_path);
-        cmd.args(request.get_arguments());
-        cmd.arg("--codegen");
-        cmd.arg(incremental_arg);
-        let output = cmd.output()?;
-        Ok(WorkResponse {
+    /// The directory rustc keeps its incremental compilation state in.
+    pub fn incremental_dir(&self) -> &std::path::Path {
+        &self.incremental_dir
+    }
+
+    /// Locks the crate's state in the shared incremental directory, or falls back to a private
+    /// directory when that fails.
+    fn lock_crate(&self, args: &RustcArgs) -> io::Result<lock::Session> {
+        let crate_name = match args.crate_name() {
+            Some(crate_name) => crate_name,
+            None => return Ok(lock::Session::Shared { _lock: None }),
+        };
+        let timeout = self.options.lock_timeout;
+        let reason = match lock::acquire(&self.incremental_dir, crate_name, timeout) {
+            Ok(Some(lock)) => return Ok(lock::Session::Shared { _lock: Some(lock) }),
+            Ok(None) => format!("another worker held it for more than {:?}", timeout),
+            Err(e) => e.to_string(),
+        };
+        let note = format!(
+            "rustc-worker: could not lock the incremental state of {} ({}), \
+             compiling it without the shared cache\n",
+            crate_name, reason
+        );
+        Ok(lock::Session::Private(
+            lock::PrivateDir::create()?,
+            Some(note),
+        ))
+    }
+
+    /// Compiles one request, running rustc in `cwd`.
+    fn handle_request(
+        &self,
+        request: WorkRequest,
+        cwd: &std::path::Path,
+    ) -> ProtobufResult<WorkResponse> {
+        let received = std::time::SystemTime::now();
+        let start = std::time::Instant::now();
+        let args = RustcArgs::new(request.get_arguments());
+        // Held until the response is ready.
+        let session = if sample::sampled(self.options.sample_rate) {
+            match self.options.sample {
+                Sample::Off => lock::Session::Disabled,
+                Sample::Cold => lock::Session::Private(lock::PrivateDir::create()?, None),
+            }
+        } else {
+            self.lock_crate(&args)?
+        };
+        let incremental_dir = match &session {
+            lock::Session::Shared { .. } => Some(self.incremental_dir.as_path()),
+            lock::Session::Private(dir, _) => Some(dir.path()),
+            lock::Session::Disabled => None,
+        };
+        let unused_deps = self.unused_deps.and_then(|lint| lint.flags(&args));
+        let timeout = self.options.timeout(args.crate_name());
+        let kind = match (&session, args.crate_name()) {
+            (lock::Session::Shared { .. }, Some(crate_name))
+                if cache::has_session(&self.incremental_dir, crate_name) =>
+            {
+                history::Kind::Warm
+            }
+            (lock::Session::Disabled, _) => history::Kind::Off,
+            _ => history::Kind::Cold,
+        };
+        let wait = start.elapsed();
+        let compile = |unused_deps: &[&str]| {
+            self.compile(&request, &args, cwd, incremental_dir, unused_deps, timeout)
+        };
+        let compiled = match compile(unused_deps.unwrap_or_default())? {
+            Ok(compiled) => compiled,
+            Err(message) => return Ok(failure(request.request_id, message)),
+        };
+        // Without --force-warn, a crate with `#![deny(warnings)]` fails on the lint the worker
+        // added, so it is compiled again as requested.
+        let mut denied = Vec::new();
+        let compiled = if self.unused_deps == Some(unused_deps::Lint::Warn)
+            && unused_deps.map_or(false, |flags| !flags.is_empty())
+            && !compiled.finished.timed_out
+            && !compiled.finished.output.status.success()
+        {
+            denied = unused_deps::denied(&compiled.stderr);
+            if denied.is_empty() {
+                compiled
+            } else {
+                drop(compiled);
+                match compile(&[])? {
+                    Ok(compiled) => compiled,
+                    Err(message) => return Ok(failure(request.request_id, message)),
+                }
+            }
+        } else {
+            compiled
+        };
+        let Compiled {
+            finished,
+            stderr,
+            mut diagnostics,
+            cgroup,
+            dep_info,
+        } = compiled;
+        let output = &finished.output;
+        let (exit_code, explanation) = if finished.timed_out {
+            (
+                child::TIMEOUT_EXIT_CODE,
+                Some(format!(
+                    "rustc-worker: rustc did not finish within {:?} and was killed\n",
+                    timeout.unwrap_or_default()
+                )),
+            )
+        } else {
+            let (exit_code, explanation) = exit_code(output.status);
+            let limit = self.limits.explain(output.status, &stderr, cgroup.as_ref());
+            (exit_code, limit.or(explanation))
+        };
+        drop(cgroup);
+        let mut response = WorkResponse {
             request_id: request.request_id,
-            exit_code: output.status.code().unwrap(),
-            output: String::from_utf8(output.stderr).expect("TODO: use the Result"),
+            exit_code,
+            output: stderr,
             ..Default::default()
-        })
+        };
+        if let Some(explanation) = explanation {
+            response.output.push_str(&explanation);
+        }
+        if let lock::Session::Private(_, Some(note)) = &session {
+            response.output.push_str(note);
+        }
+        if finished.timed_out {
+            // A private directory goes away by itself. The shared one is still locked for the
+            // crate, so nobody else can be using the session rustc was writing.
+            if let (lock::Session::Shared { .. }, Some(crate_name)) = (&session, args.crate_name())
+            {
+                if let Err(e) = cache::remove_working_sessions(&self.incremental_dir, crate_name) {
+                    response.output.push_str(&format!(
+                        "warning: could not remove the interrupted incremental session: {}\n",
+                        e
+                    ));
+                }
+            }
+        }
+        if let Some(dep_info) = dep_info {
+            if response.exit_code == 0 {
+                self.check_hermeticity(&request, cwd, dep_info.path(), &mut response);
+            }
+        }
+        if self.options.verify != Verify::Off
+            && response.exit_code == 0
+            && incremental_dir.is_some()
+            && sample::sampled(self.options.verify_rate)
+        {
+            self.verify(&args, cwd, timeout, &mut response);
+        }
+        if let (Some(report_dir), Some(_)) = (&self.options.unused_deps_report, unused_deps) {
+            self.report_unused_deps(report_dir, &args, denied, &mut response);
+            if let Some(diagnostics) = &mut diagnostics {
+                if !unused_deps::requested(&args) {
+                    unused_deps::strip_diagnostics(diagnostics);
+                }
+            }
+        }
+        if let (Some(sarif), Some(diagnostics)) = (&self.options.sarif, &diagnostics) {
+            let written = sarif::write(sarif, &args, diagnostics, self.locations.as_ref());
+            if let Err(e) = written {
+                response
+                    .output
+                    .push_str(&format!("warning: could not write SARIF report: {}\n", e));
+            }
+        }
+        if let Some(diagnostics) = diagnostics.filter(|_| self.options.json_diagnostics) {
+            if let Err(e) = diagnostics::write(cwd, &args, diagnostics) {
+                response
+                    .output
+                    .push_str(&format!("warning: could not write diagnostics: {}\n", e));
+            }
+        }
+        if let Some(locations) = &self.locations {
+            response.output = locations.rewrite(&response.output);
+        }
+        if stats::verbosity(&request) >= stats::VERBOSE {
+            response
+                .output
+                .push_str(&stats::describe(args.crate_name(), &finished.usage));
+        }
+        if let Some(sink) = &self.stats {
+            let written = sink.write(
+                response.request_id,
+                args.crate_name(),
+                response.exit_code,
+                &finished.usage,
+            );
+            if let Err(e) = written {
+                response
+                    .output
+                    .push_str(&format!("warning: could not write resource usage: {}\n", e));
+            }
+        }
+        if let Some(trace) = &self.trace {
+            let traced = trace.request(&trace::Request {
+                crate_name: args.crate_name(),
+                request_id: response.request_id,
+                received,
+                wait,
+                rustc: finished.usage.wall,
+                incremental: matches!(session, lock::Session::Shared { .. }),
+                exit_code: response.exit_code,
+            });
+            if let Err(e) = traced {
+                response
+                    .output
+                    .push_str(&format!("warning: could not write trace: {}\n", e));
+            }
+        }
+        if let Some(crate_name) = args.crate_name() {
+            // The manifest, invocations, history and timings are bookkeeping, so failing to
+            // update them should not fail the build.
+            let _ = manifest::touch_crate(&self.incremental_dir, crate_name);
+            let _ = invocations::record(
+                &self.incremental_dir,
+                cwd,
+                request.get_arguments(),
+                finished.usage.wall,
+            );
+            let _ = history::record(
+                &self.incremental_dir,
+                crate_name,
+                kind,
+                finished.usage.wall,
+                response.exit_code,
+            );
+            let _ = timings::record(
+                &self.incremental_dir,
+                &timings::Request {
+                    crate_name,
+                    args: &args,
+                    start: received + wait,
+                    duration: finished.usage.wall,
+                    exit_code: response.exit_code,
+                },
+            );
+        }
+        Ok(response)
+    }
+
+    /// Runs rustc for the request, adding the `unused_deps` flags. Errors setting up the request
+    /// fail the worker, while an `Err` message only fails the request.
+    fn compile(
+        &self,
+        request: &WorkRequest,
+        args: &RustcArgs,
+        cwd: &std::path::Path,
+        incremental_dir: Option<&std::path::Path>,
+        unused_deps: &[&str],
+        timeout: Option<std::time::Duration>,
+    ) -> io::Result<Result<Compiled, String>> {
+        let mut cmd = std::process::Command::new(&self.program_path);
+        cmd.args(request.get_arguments());
+        cmd.current_dir(cwd);
+        if let Some(incremental_dir) = incremental_dir {
+            let mut incremental_arg = std::ffi::OsString::from("incremental=");
+            incremental_arg.push(incremental_dir);
+            cmd.arg("--codegen");
+            cmd.arg(incremental_arg);
+        }
+        // Without declared inputs there is nothing to check against.
+        let dep_info =
+            if self.options.hermeticity != Hermeticity::Off && !request.get_inputs().is_empty() {
+                Some(hermeticity::DepInfo::prepare(args, &mut cmd)?)
+            } else {
+                None
+            };
+        cmd.args(unused_deps);
+        let json_diagnostics = (self.options.json_diagnostics || self.options.sarif.is_some())
+            && diagnostics::prepare(args, &mut cmd);
+        // The worker has to keep serving requests, so only this one fails.
+        let cgroup = match self.limits.apply(&mut cmd) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                return Ok(Err(format!(
+                    "rustc-worker: could not apply the resource limits: {}\n",
+                    e
+                )))
+            }
+        };
+        let finished = match child::run(&mut cmd, timeout) {
+            Ok(finished) => finished,
+            // Includes failing to move rustc into its cgroup.
+            Err(e) => return Ok(Err(format!("rustc-worker: could not run rustc: {}\n", e))),
+        };
+        // Bazel expects UTF-8, so replace whatever else rustc or a linker printed.
+        let stderr = String::from_utf8_lossy(&finished.output.stderr).into_owned();
+        let (stderr, diagnostics) = if json_diagnostics {
+            let rendered = diagnostics::render(&stderr, self.options.color);
+            (rendered.output, Some(rendered.diagnostics))
+        } else {
+            (stderr, None)
+        };
+        Ok(Ok(Compiled {
+            finished,
+            stderr,
+            diagnostics,
+            cgroup,
+            dep_info,
+        }))
+    }
+
+    fn report_unused_deps(
+        &self,
+        report_dir: &std::path::Path,
+        args: &RustcArgs,
+        denied: Vec<String>,
+        response: &mut WorkResponse,
+    ) {
+        // rustc only checks for unused crates once the crate compiled.
+        if response.exit_code == 0 {
+            let mut unused = unused_deps::parse(&response.output);
+            unused.extend(denied);
+            if let Err(e) = unused_deps::write_report(report_dir, args, &unused) {
+                response.output.push_str(&format!(
+                    "warning: could not write unused dependency report: {}\n",
+                    e
+                ));
+            }
+        }
+        if !unused_deps::requested(args) {
+            response.output = unused_deps::strip(&response.output);
+        }
+    }
+
+    fn verify(
+        &self,
+        args: &RustcArgs,
+        cwd: &std::path::Path,
+        timeout: Option<std::time::Duration>,
+        response: &mut WorkResponse,
+    ) {
+        let verified = verify::verify(
+            &self.program_path,
+            cwd,
+            args,
+            self.options.verify,
+            self.options.verify_bundles.as_deref(),
+            &self.limits,
+            timeout,
+        );
+        match verified {
+            Ok(None) => {}
+            Ok(Some(report)) => {
+                response.output.push_str(&report);
+                if self.options.verify == Verify::Error {
+                    response.exit_code = 1;
+                }
+            }
+            Err(e) => response.output.push_str(&format!(
+                "warning: could not verify the incremental compilation: {}\n",
+                e
+            )),
+        }
+    }
+
+    fn check_hermeticity(
+        &self,
+        request: &WorkRequest,
+        cwd: &std::path::Path,
+        dep_info: &std::path::Path,
+        response: &mut WorkResponse,
+    ) {
+        let undeclared = match hermeticity::undeclared_reads(cwd, dep_info, request.get_inputs()) {
+            Ok(undeclared) => undeclared,
+            Err(e) => {
+                response.output.push_str(&format!(
+                    "warning: could not check inputs using {}: {}\n",
+                    dep_info.display(),
+                    e
+                ));
+                return;
+            }
+        };
+        if undeclared.is_empty() {
+            return;
+        }
+        response
+            .output
+            .push_str(&hermeticity::report(self.options.hermeticity, &undeclared));
+        if self.options.hermeticity == Hermeticity::Error {
+            response.exit_code = 1;
+        }
     }
 
     pub fn main_loop<R: io::Read, W: io::Write>(
@@ -63,15 +692,37 @@ impl Worker {
         reader: &mut R,
         writer: &mut W,
     ) -> ProtobufResult<()> {
+        let mut recorder = match &self.options.record {
+            Some(path) => match record::Recorder::open(path, self) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => {
+                    eprintln!("rustc-worker: not recording requests: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
         let mut stream = CodedInputStream::new(reader);
         loop {
+            // Bazel closes stdin to shut the worker down.
+            if stream.eof()? {
+                return Ok(());
+            }
             let msg_len = stream.read_raw_varint32()?;
             let limit = stream.push_limit(msg_len as u64)?;
             let mut message = WorkRequest::default();
             message.merge_from(&mut stream)?;
             stream.pop_limit(limit);
 
-            let response = self.handle_request(message)?;
+            // The log is bookkeeping, so failing to write it should not fail the build.
+            if let Some(recorder) = recorder.as_mut() {
+                let _ = recorder.request(&message);
+            }
+            let start = std::time::Instant::now();
+            let response = self.handle_request(message, &self.cwd)?;
+            if let Some(recorder) = recorder.as_mut() {
+                let _ = recorder.response(&response, start.elapsed());
+            }
             let mut output_stream = CodedOutputStream::new(writer);
             output_stream.write_raw_varint32(response.compute_size())?;
             response.write_to_with_cached_sizes(&mut output_stream)?;
@@ -94,8 +745,50 @@ impl Worker {
     }
 }
 
+/// The lines of a text, each with its newline if it has one.
+pub(crate) fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while let Some(end) = rest.find('\n') {
+        lines.push(&rest[..=end]);
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    lines
+}
+
 #[cfg(test)]
 mod test {
+    use super::detect_output_base;
+    use super::Worker;
+    use std::path::Path;
+
     #[test]
-    fn test_eof() {}
+    fn test_detect_output_base() {
+        let output_base = Path::new("/home/user/.cache/bazel/_bazel_user/0123abcd");
+        assert_eq!(
+            detect_output_base(&output_base.join("execroot/__main__")),
+            output_base
+        );
+        assert_eq!(
+            detect_output_base(&output_base.join("bazel-workers/worker-1-Rustc")),
+            output_base
+        );
+        assert_eq!(
+            detect_output_base(Path::new("/src/repo")),
+            Path::new("/src/repo")
+        );
+    }
+
+    #[test]
+    fn test_eof() {
+        let worker = Worker::new("rustc".into(), "rustc-test-eof".into(), "fastbuild").unwrap();
+        let mut output = Vec::new();
+        let result = worker.main_loop(&mut &[][..], &mut output);
+        std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+        assert!(result.is_ok());
+        assert!(output.is_empty());
+    }
 }
@@ -0,0 +1,305 @@
+//! Memory and CPU limits for rustc, so that one huge crate can't take the whole host down.
+//!
+//! Limits are always set with `setrlimit`. When the worker runs in a cgroup v2 that was
+//! delegated to it, each request also gets a leaf cgroup of its own, which limits the linker
+//! and anything else rustc starts too, and tells us when the kernel OOM-killed rustc.
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+static LEAF_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(Debug)]
+pub(crate) struct Limits {
+    /// Bytes of memory.
+    memory: Option<u64>,
+    cpu: Option<Duration>,
+    /// The delegated cgroup to create a leaf in for each request.
+    cgroup: Option<PathBuf>,
+}
+
+/// The cgroup v2 the worker is in, from `/proc/self/cgroup`.
+fn own_cgroup() -> Option<PathBuf> {
+    let contents = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = contents.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let root = Path::new(CGROUP_ROOT);
+    // Only cgroup v2 has this file at the root of the hierarchy.
+    if !root.join("cgroup.controllers").is_file() {
+        return None;
+    }
+    Some(root.join(path.trim_start_matches('/')))
+}
+
+/// Whether the worker may write to a file, as it may to the files of a cgroup delegated to it.
+#[cfg(unix)]
+fn writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn writable(_path: &Path) -> bool {
+    false
+}
+
+/// Prepares the worker's cgroup for a leaf per request, or says why it can't.
+///
+/// A cgroup that has processes of its own can't have controllers enabled for its children, so
+/// the worker first moves itself into a `worker` leaf. Other workers in the same cgroup do the
+/// same and share it. If the controller can't be enabled after all, the worker moves back.
+fn delegated_cgroup() -> Result<PathBuf, String> {
+    let cgroup = own_cgroup().ok_or("the worker is not in a cgroup v2")?;
+    let controllers = std::fs::read_to_string(cgroup.join("cgroup.controllers")).map_err(|e| {
+        format!(
+            "could not read the controllers of {}: {}",
+            cgroup.display(),
+            e
+        )
+    })?;
+    if !controllers.split_whitespace().any(|c| c == "memory") {
+        return Err(format!(
+            "the memory controller is not available in {}",
+            cgroup.display()
+        ));
+    }
+    let files = [
+        cgroup.clone(),
+        cgroup.join("cgroup.procs"),
+        cgroup.join("cgroup.subtree_control"),
+    ];
+    if !files.iter().all(|file| writable(file)) {
+        return Err(format!(
+            "{} is not delegated to the worker",
+            cgroup.display()
+        ));
+    }
+    let worker = cgroup.join("worker");
+    match std::fs::create_dir(&worker) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(format!("could not create {}: {}", worker.display(), e)),
+    }
+    let pid = std::process::id().to_string();
+    if let Err(e) = std::fs::write(worker.join("cgroup.procs"), &pid) {
+        let _ = std::fs::remove_dir(&worker);
+        return Err(format!("could not move into {}: {}", worker.display(), e));
+    }
+    if let Err(e) = std::fs::write(cgroup.join("cgroup.subtree_control"), "+memory") {
+        let _ = std::fs::write(cgroup.join("cgroup.procs"), &pid);
+        // Fails while other workers are still in it, which is fine.
+        let _ = std::fs::remove_dir(&worker);
+        return Err(format!(
+            "could not enable the memory controller in {}: {}",
+            cgroup.display(),
+            e
+        ));
+    }
+    Ok(cgroup)
+}
+
+/// A cgroup holding rustc and its children for one request, removed when dropped.
+pub(crate) struct Leaf {
+    path: PathBuf,
+}
+
+impl Leaf {
+    fn create(parent: &Path, memory: Option<u64>) -> io::Result<Self> {
+        let path = parent.join(format!(
+            "rustc-{}-{}",
+            std::process::id(),
+            LEAF_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir(&path)?;
+        let leaf = Leaf { path };
+        if let Some(memory) = memory {
+            std::fs::write(leaf.path.join("memory.max"), memory.to_string())?;
+            // Swapping only postpones the OOM kill and makes the host crawl.
+            let _ = std::fs::write(leaf.path.join("memory.swap.max"), "0");
+        }
+        Ok(leaf)
+    }
+
+    /// Whether the kernel killed a process in the leaf for running out of memory.
+    pub(crate) fn oom_killed(&self) -> bool {
+        let events = std::fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        events.lines().any(|line| {
+            line.strip_prefix("oom_kill ")
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .map_or(false, |count| count > 0)
+        })
+    }
+}
+
+impl Drop for Leaf {
+    fn drop(&mut self) {
+        // Only succeeds once every process in the leaf has exited, which the process group kill
+        // on timeouts takes care of.
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn rlimit(limit: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    }
+}
+
+#[cfg(unix)]
+fn check(result: std::os::raw::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Writes to `cgroup.procs` from the child between fork and exec, which moves the child into
+/// the cgroup before rustc allocates anything.
+#[cfg(unix)]
+fn join_cgroup(procs: &std::ffi::CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written == 1 {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn new(memory: Option<u64>, cpu: Option<Duration>) -> Self {
+        let cgroup = match memory {
+            Some(_) => match delegated_cgroup() {
+                Ok(cgroup) => Some(cgroup),
+                Err(reason) => {
+                    eprintln!(
+                        "rustc-worker: limiting memory with setrlimit only: {}",
+                        reason
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        Limits {
+            memory,
+            cpu,
+            cgroup,
+        }
+    }
+
+    /// Makes the command run under the limits. Returns the cgroup it will run in, if any.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self, cmd: &mut Command) -> io::Result<Option<Leaf>> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::process::CommandExt;
+
+        if self.memory.is_none() && self.cpu.is_none() {
+            return Ok(None);
+        }
+        let leaf = match &self.cgroup {
+            Some(cgroup) => Some(Leaf::create(cgroup, self.memory)?),
+            None => None,
+        };
+        let procs = match &leaf {
+            Some(leaf) => Some(
+                std::ffi::CString::new(leaf.path.join("cgroup.procs").as_os_str().as_bytes())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+            None => None,
+        };
+        let memory = self.memory;
+        let cpu = self.cpu;
+        // Only async-signal-safe calls are allowed between fork and exec.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    join_cgroup(procs)?;
+                }
+                if let Some(memory) = memory {
+                    // RLIMIT_AS would count the address space rustc reserves but never uses.
+                    check(libc::setrlimit(libc::RLIMIT_DATA, &rlimit(memory)))?;
+                }
+                if let Some(cpu) = cpu {
+                    check(libc::setrlimit(
+                        libc::RLIMIT_CPU,
+                        &rlimit(cpu.as_secs().max(1)),
+                    ))?;
+                }
+                Ok(())
+            });
+        }
+        Ok(leaf)
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn apply(&self, _cmd: &mut Command) -> io::Result<Option<Leaf>> {
+        Ok(None)
+    }
+
+    /// Explains why rustc died when it was because of a limit, rather than leaving the user
+    /// with an unexplained signal.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn explain(
+        &self,
+        status: std::process::ExitStatus,
+        stderr: &str,
+        leaf: Option<&Leaf>,
+    ) -> Option<String> {
+        if leaf.map_or(false, Leaf::oom_killed) {
+            return Some(format!(
+                "rustc-worker: rustc ran out of memory and was killed by the kernel \
+                 (memory limit {})\n",
+                format_memory(self.memory)
+            ));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            let signal = status.signal();
+            // Rust's allocation error handler prints this and aborts.
+            if signal == Some(libc::SIGABRT) && stderr.contains("memory allocation of ") {
+                if let Some(memory) = self.memory {
+                    return Some(format!(
+                        "rustc-worker: rustc ran out of memory (memory limit {})\n",
+                        format_memory(Some(memory))
+                    ));
+                }
+            }
+            if let (Some(libc::SIGXCPU), Some(cpu)) = (signal, self.cpu) {
+                return Some(format!(
+                    "rustc-worker: rustc used more than {:?} of CPU time and was killed\n",
+                    cpu
+                ));
+            }
+        }
+        None
+    }
+}
+
+fn format_memory(memory: Option<u64>) -> String {
+    match memory {
+        Some(bytes) => format!("{} MiB", bytes / (1024 * 1024)),
+        None => "none".to_string(),
+    }
+}
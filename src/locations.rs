@@ -0,0 +1,190 @@
+//! Rewrites the source locations in rustc diagnostics (the ` --> src/lib.rs:1:2` lines) so that
+//! editors and terminals can open them from the workspace.
+//!
+//! rustc runs in the execroot, a sandbox or a worker directory, and names files relative to it
+//! or by absolute paths inside it. Sources of the main repository are mirrored at the same
+//! relative paths in the workspace, so those become workspace-relative. Sources of external
+//! repositories only exist in the output base, so those become absolute paths there. Generated
+//! files under `bazel-out/` are left alone, as the workspace has a `bazel-out` symlink.
+
+use crate::diagnostics;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) struct Locations {
+    workspace_root: PathBuf,
+    /// The directory rustc runs in.
+    execroot: PathBuf,
+    output_base: PathBuf,
+}
+
+/// The part of an absolute path after `.../execroot/<workspace>/` or, in sandboxed workers,
+/// `.../bazel-workers/<worker>/<workspace>/`.
+fn execroot_relative(path: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+        if component == Component::Normal("execroot".as_ref()) {
+            components.next()?;
+            return Some(components.collect());
+        }
+        if component == Component::Normal("bazel-workers".as_ref()) {
+            components.next()?;
+            components.next()?;
+            return Some(components.collect());
+        }
+    }
+    None
+}
+
+/// Skips the ANSI escape sequences at the start of `text`, returning how many bytes they take.
+fn ansi_prefix_len(text: &str) -> usize {
+    let mut len = 0;
+    while let Some(rest) = text[len..].strip_prefix("\u{1b}[") {
+        match rest.find(|c: char| c.is_ascii_alphabetic()) {
+            Some(end) => len += 2 + end + 1,
+            None => break,
+        }
+    }
+    len
+}
+
+impl Locations {
+    pub(crate) fn new(workspace_root: PathBuf, execroot: PathBuf, output_base: PathBuf) -> Self {
+        Locations {
+            workspace_root,
+            execroot,
+            output_base,
+        }
+    }
+
+    /// The path to show for a file rustc names, or `None` if it is outside of Bazel's
+    /// directories.
+    pub(crate) fn rewrite_path(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let relative = if path.is_absolute() {
+            if let Ok(relative) = path.strip_prefix(&self.workspace_root) {
+                return Some(relative.to_owned());
+            }
+            match path.strip_prefix(&self.execroot) {
+                Ok(relative) => relative.to_owned(),
+                Err(_) => execroot_relative(path)?,
+            }
+        } else {
+            path.to_owned()
+        };
+        match relative.strip_prefix("external") {
+            Ok(external) => Some(self.output_base.join("external").join(external)),
+            Err(_) => Some(relative),
+        }
+    }
+
+    /// Rewrites the location in a ` --> path:line:column` or ` ::: path:line:column` line.
+    fn rewrite_line(&self, line: &str) -> Option<String> {
+        let marker = line.find("--> ").or_else(|| line.find("::: "))?;
+        // Only whitespace and colors can come before the marker.
+        if !diagnostics::strip_ansi(&line[..marker]).trim().is_empty() {
+            return None;
+        }
+        let start = marker + 4;
+        let start = start + ansi_prefix_len(&line[start..]);
+        let end = line[start..]
+            .find(|c: char| c.is_whitespace() || c == '\u{1b}')
+            .map_or(line.len(), |end| start + end);
+        let location = &line[start..end];
+        // Line and column come after the last two colons.
+        let mut parts = location.rsplitn(3, ':');
+        let column = parts.next()?;
+        let line_number = parts.next()?;
+        let path = parts.next()?;
+        let path = self.rewrite_path(path)?;
+        Some(format!(
+            "{}{}:{}:{}{}",
+            &line[..start],
+            path.display(),
+            line_number,
+            column,
+            &line[end..]
+        ))
+    }
+
+    /// Rewrites every location in rustc's output.
+    pub(crate) fn rewrite(&self, output: &str) -> String {
+        crate::split_lines(output)
+            .into_iter()
+            .map(|line| match self.rewrite_line(line) {
+                Some(rewritten) => rewritten,
+                None => line.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Locations;
+    use std::path::PathBuf;
+
+    const OUTPUT_BASE: &str = "/home/user/.cache/bazel/_bazel_user/0123abcd";
+
+    fn locations() -> Locations {
+        Locations::new(
+            "/home/user/project".into(),
+            PathBuf::from(OUTPUT_BASE).join("execroot/__main__"),
+            OUTPUT_BASE.into(),
+        )
+    }
+
+    #[test]
+    fn test_rewrite_path() {
+        let locations = locations();
+        let rewritten = |path: &str| locations.rewrite_path(path).unwrap();
+        assert_eq!(rewritten("src/lib.rs"), PathBuf::from("src/lib.rs"));
+        assert_eq!(
+            rewritten(&format!("{}/execroot/__main__/src/lib.rs", OUTPUT_BASE)),
+            PathBuf::from("src/lib.rs")
+        );
+        assert_eq!(
+            rewritten(&format!(
+                "{}/sandbox/linux-sandbox/12/execroot/__main__/src/lib.rs",
+                OUTPUT_BASE
+            )),
+            PathBuf::from("src/lib.rs")
+        );
+        assert_eq!(
+            rewritten("external/serde/src/lib.rs"),
+            PathBuf::from(OUTPUT_BASE).join("external/serde/src/lib.rs")
+        );
+        assert_eq!(
+            rewritten("bazel-out/k8-fastbuild/bin/gen.rs"),
+            PathBuf::from("bazel-out/k8-fastbuild/bin/gen.rs")
+        );
+        assert_eq!(
+            rewritten("/home/user/project/src/main.rs"),
+            PathBuf::from("src/main.rs")
+        );
+        assert_eq!(locations.rewrite_path("/usr/lib/rustlib/src/lib.rs"), None);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let locations = locations();
+        let output = format!(
+            "error[E0425]: cannot find value `x` in this scope\n \
+             --> {}/sandbox/linux-sandbox/3/execroot/__main__/src/lib.rs:1:32\n  \
+             |\n\
+             \x1b[1m\x1b[94m   ::: \x1b[0mexternal/serde/src/de.rs:10:5\x1b[0m\n",
+            OUTPUT_BASE
+        );
+        assert_eq!(
+            locations.rewrite(&output),
+            format!(
+                "error[E0425]: cannot find value `x` in this scope\n \
+                 --> src/lib.rs:1:32\n  \
+                 |\n\
+                 \x1b[1m\x1b[94m   ::: \x1b[0m{}/external/serde/src/de.rs:10:5\x1b[0m\n",
+                OUTPUT_BASE
+            )
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Advisory locks that keep workers from using a crate's incremental state at the same time.
+//!
+//! Bazel normally never compiles the same crate twice at once, but dynamic execution, exec and
+//! target configurations of the same crate, and separate Bazel clients sharing an output base
+//! all break that assumption.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+static PRIVATE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How often to retry a lock that is held by another worker.
+const RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Where a request keeps its incremental state.
+pub(crate) enum Session {
+    /// The shared cache, with the crate locked if the request names one.
+    Shared { _lock: Option<CrateLock> },
+    /// A private directory, with a note on why the shared cache could not be used when the
+    /// worker didn't choose to.
+    Private(PrivateDir, Option<String>),
+    /// No incremental compilation.
+    Disabled,
+}
+
+/// Holds the lock on a crate until dropped.
+pub(crate) struct CrateLock {
+    _file: File,
+}
+
+/// The lock file for a crate. rustc keeps the crate's sessions in a directory named after the
+/// crate and a hash we don't know in advance, so the lock lives next to it instead.
+fn lock_path(cache: &Path, crate_name: &str) -> PathBuf {
+    cache.join(format!("{}.lock", crate_name))
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Blocks until the file is locked. The lock is released when the file is closed.
+#[cfg(unix)]
+pub(crate) fn lock_file(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock_file(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Locks a crate's incremental state in `cache`, waiting up to `timeout` for another worker to
+/// release it. Returns `None` if it is still held by then.
+pub(crate) fn acquire(
+    cache: &Path,
+    crate_name: &str,
+    timeout: Duration,
+) -> io::Result<Option<CrateLock>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(cache, crate_name))?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if try_lock(&file)? {
+            return Ok(Some(CrateLock { _file: file }));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(RETRY_INTERVAL.min(deadline - now));
+    }
+}
+
+/// An incremental directory for one request, removed when dropped. Compiling with it is as slow
+/// as a clean build, but doesn't have to wait for the shared one.
+pub(crate) struct PrivateDir {
+    path: PathBuf,
+}
+
+impl PrivateDir {
+    pub(crate) fn create() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "rustc-worker-private-{}-{}",
+            std::process::id(),
+            PRIVATE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        // Fails if the path exists, so nobody else can have prepared it.
+        builder.create(&path)?;
+        Ok(PrivateDir { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::acquire;
+    use std::time::Duration;
+
+    #[test]
+    fn test_acquire() {
+        let cache =
+            std::env::temp_dir().join(format!("rustc-worker-lock-test-{}", std::process::id()));
+        std::fs::create_dir_all(&cache).unwrap();
+        let held = acquire(&cache, "foo", Duration::from_secs(0)).unwrap();
+        assert!(held.is_some());
+        // Locks are per open file, so a second one conflicts even within a process.
+        assert!(acquire(&cache, "foo", Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+        assert!(acquire(&cache, "bar", Duration::from_secs(0))
+            .unwrap()
+            .is_some());
+        drop(held);
+        assert!(acquire(&cache, "foo", Duration::from_secs(0))
+            .unwrap()
+            .is_some());
+        std::fs::remove_dir_all(cache).unwrap();
+    }
+}
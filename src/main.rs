@@ -1,10 +1,346 @@
+use protobuf::Message;
 use protobuf::ProtobufResult;
+use rustc_worker::Options;
+use std::ffi::OsString;
+use std::iter::Peekable;
+
+/// Takes the next argument if it is a flag for the worker itself.
+fn next_worker_flag<I: Iterator<Item = OsString>>(args: &mut Peekable<I>) -> Option<String> {
+    let is_flag = match args.peek().and_then(|arg| arg.to_str()) {
+        Some(arg) => arg.starts_with("--") && arg != "--persistent_worker",
+        None => false,
+    };
+    if is_flag {
+        args.next().and_then(|arg| arg.into_string().ok())
+    } else {
+        None
+    }
+}
+
+fn apply_flag(options: &mut Options, flag: &str) {
+    let mut parts = flag.splitn(2, '=');
+    let name = parts.next().unwrap();
+    let value = parts
+        .next()
+        .unwrap_or_else(|| panic!("{} requires a value", name));
+    match name {
+        "--hermeticity" => {
+            options.hermeticity = value.parse().unwrap_or_else(|e| panic!("{}", e));
+        }
+        "--unused_deps_report" => options.unused_deps_report = Some(value.into()),
+        "--record" => options.record = Some(value.into()),
+        "--output_base" => options.output_base = Some(value.into()),
+        "--cache_root" => options.cache_root = Some(value.into()),
+        "--lock_timeout" => {
+            let secs = value.parse().expect("lock timeout in seconds");
+            options.lock_timeout = std::time::Duration::from_secs(secs);
+        }
+        "--timeout" => {
+            let secs = value.parse().expect("timeout in seconds");
+            options.timeout = Some(std::time::Duration::from_secs(secs));
+        }
+        "--crate_timeout" => {
+            let mut parts = value.splitn(2, '=');
+            let crate_name = parts.next().unwrap();
+            let secs = parts
+                .next()
+                .expect("--crate_timeout=<crate name>=<seconds>")
+                .parse()
+                .expect("timeout in seconds");
+            options
+                .crate_timeouts
+                .push((crate_name.to_string(), std::time::Duration::from_secs(secs)));
+        }
+        "--memory_limit" => {
+            let mib: u64 = value.parse().expect("memory limit in MiB");
+            options.memory_limit = Some(mib * 1024 * 1024);
+        }
+        "--cpu_limit" => {
+            let secs = value.parse().expect("CPU time limit in seconds");
+            options.cpu_limit = Some(std::time::Duration::from_secs(secs));
+        }
+        "--stats" => options.stats = Some(value.into()),
+        "--trace" => options.trace = Some(value.into()),
+        "--sample_rate" => {
+            options.sample_rate = value.parse().expect("fraction of requests to sample");
+        }
+        "--sample" => options.sample = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+        "--verify" => options.verify = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+        "--verify_rate" => {
+            options.verify_rate = value.parse().expect("fraction of requests to verify");
+        }
+        "--verify_bundles" => options.verify_bundles = Some(value.into()),
+        "--json_diagnostics" => {
+            options.json_diagnostics = value.parse().expect("--json_diagnostics=true|false");
+        }
+        "--color" => options.color = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+        "--workspace_root" => options.workspace_root = Some(value.into()),
+        "--sarif" => options.sarif = Some(value.into()),
+        _ => panic!("unknown flag {}", name),
+    }
+}
+
+/// `rustc-worker replay [flags] <log>` runs the requests recorded with `--record` again.
+fn replay<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut args = args.peekable();
+    let mut options = Options::default();
+    while let Some(flag) = next_worker_flag(&mut args) {
+        apply_flag(&mut options, &flag);
+    }
+    let log = args.next().expect("log file");
+    let stdout = std::io::stdout();
+    if !rustc_worker::replay(log.as_ref(), options, &mut stdout.lock())? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `rustc-worker decode requests|responses [file]` prints captured worker traffic as JSON.
+fn decode<I: Iterator<Item = OsString>>(mut args: I) -> ProtobufResult<()> {
+    let stream: rustc_worker::Stream = args
+        .next()
+        .and_then(|arg| arg.into_string().ok())
+        .expect("stream kind")
+        .parse()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let stdout = std::io::stdout();
+    let stderr = std::io::stderr();
+    let clean = match args.next() {
+        Some(path) => rustc_worker::decode(
+            stream,
+            &mut std::fs::File::open(path)?,
+            &mut stdout.lock(),
+            &mut stderr.lock(),
+        )?,
+        None => rustc_worker::decode(
+            stream,
+            &mut std::io::stdin().lock(),
+            &mut stdout.lock(),
+            &mut stderr.lock(),
+        )?,
+    };
+    if !clean {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `rustc-worker send [flags] <worker> [startup args...] -- <arguments...>` starts a worker,
+/// sends it one request and prints the response.
+fn send<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut args = args.map(|arg| arg.into_string().expect("arguments must be valid utf-8"));
+    let mut request = rustc_worker::WorkRequest::default();
+    let mut timeout = std::time::Duration::from_secs(600);
+    let mut worker = None;
+    let mut startup_args = Vec::new();
+    for arg in &mut args {
+        if arg == "--" {
+            break;
+        }
+        if worker.is_some() {
+            startup_args.push(arg);
+            continue;
+        }
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next().unwrap(), parts.next()) {
+            ("--timeout", Some(secs)) => {
+                timeout = std::time::Duration::from_secs(secs.parse().expect("timeout in seconds"))
+            }
+            ("--request_id", Some(id)) => request.set_request_id(id.parse().expect("request id")),
+            ("--verbosity", Some(level)) => {
+                // Field 5, newer than our generated protocol code, so set as an unknown field.
+                let level = level.parse().expect("verbosity");
+                request.mut_unknown_fields().add_varint(5, level);
+            }
+            ("--input", Some(path)) => {
+                let mut input = rustc_worker::Input::default();
+                input.set_path(path.to_string());
+                request.mut_inputs().push(input);
+            }
+            _ => worker = Some(arg),
+        }
+    }
+    request.mut_arguments().extend(args);
+
+    let mut client =
+        rustc_worker::WorkerClient::spawn(worker.expect("worker binary"), startup_args)?;
+    let response = client.request(&request, timeout)?;
+    client.shutdown()?;
+    eprint!("{}", response.get_output());
+    std::process::exit(response.get_exit_code());
+}
+
+/// `rustc-worker cache list|inspect|prune|clean` manages the incremental caches.
+fn cache<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut args = args.map(|arg| arg.into_string().expect("arguments must be valid utf-8"));
+    let command = args.next().unwrap_or_else(|| "list".to_string());
+    let mut max_age = None;
+    let mut max_size = None;
+    let mut dry_run = false;
+    let mut all = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next().unwrap(), parts.next()) {
+            ("--older_than", Some(days)) => {
+                let days: u64 = days.parse().expect("age in days");
+                max_age = Some(std::time::Duration::from_secs(days * 24 * 60 * 60));
+            }
+            ("--max_size", Some(mib)) => {
+                let mib: u64 = mib.parse().expect("size in MiB");
+                max_size = Some(mib * 1024 * 1024);
+            }
+            ("--dry_run", None) => dry_run = true,
+            ("--all", None) => all = true,
+            (flag, _) if flag.starts_with("--") => panic!("unknown flag {}", flag),
+            _ => paths.push(arg),
+        }
+    }
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match command.as_str() {
+        "list" => rustc_worker::cache::list(&mut out)?,
+        "inspect" => {
+            let cache = paths.pop().expect("cache directory");
+            rustc_worker::cache::inspect(cache.as_ref(), &mut out)?
+        }
+        "prune" => {
+            assert!(
+                max_age.is_some() || max_size.is_some(),
+                "prune needs --older_than=<days> or --max_size=<MiB>"
+            );
+            rustc_worker::cache::prune(max_age, max_size, dry_run, &mut out)?
+        }
+        "clean" => rustc_worker::cache::clean(all, dry_run, &mut out)?,
+        _ => panic!("unknown cache command {}", command),
+    }
+    Ok(())
+}
+
+/// `rustc-worker stats [cache...]` summarizes the compile times recorded in the caches.
+fn stats<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let caches: Vec<std::path::PathBuf> = args.map(Into::into).collect();
+    let stdout = std::io::stdout();
+    rustc_worker::history::summarize(&caches, &mut stdout.lock())?;
+    Ok(())
+}
+
+/// `rustc-worker rust-project [--workspace_root=<dir>] [cache...]` prints a `rust-project.json`
+/// for the crates the workers compiled.
+fn rust_project<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut workspace_root = None;
+    let mut caches = Vec::new();
+    for arg in args {
+        let arg = arg.into_string().expect("arguments must be valid utf-8");
+        match arg.strip_prefix("--workspace_root=") {
+            Some(dir) => workspace_root = Some(std::path::PathBuf::from(dir)),
+            None if arg.starts_with("--") => panic!("unknown flag {}", arg),
+            None => caches.push(arg.into()),
+        }
+    }
+    let stdout = std::io::stdout();
+    rustc_worker::rust_project::generate(&caches, workspace_root.as_deref(), &mut stdout.lock())?;
+    Ok(())
+}
+
+/// `rustc-worker timings [cache...]` prints an HTML report of when the crates of the last build
+/// compiled.
+fn timings<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let caches: Vec<std::path::PathBuf> = args.map(Into::into).collect();
+    let stdout = std::io::stdout();
+    rustc_worker::timings::report_html(&caches, &mut stdout.lock())?;
+    Ok(())
+}
+
+/// `rustc-worker graph [--format=dot|json] [cache...]` prints the crate graph of the build with
+/// compile times.
+fn graph<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut format = rustc_worker::graph::Format::default();
+    let mut caches = Vec::new();
+    for arg in args {
+        let arg = arg.into_string().expect("arguments must be valid utf-8");
+        match arg.strip_prefix("--format=") {
+            Some(value) => format = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+            None if arg.starts_with("--") => panic!("unknown flag {}", arg),
+            None => caches.push(arg.into()),
+        }
+    }
+    let stdout = std::io::stdout();
+    rustc_worker::graph::export(&caches, format, &mut stdout.lock())?;
+    Ok(())
+}
+
+/// `rustc-worker check [--workspace_root=<dir>] <crate-name> [cache...]` type-checks a crate
+/// again and prints its diagnostics as JSON, for rust-analyzer's flycheck.
+fn check<I: Iterator<Item = OsString>>(args: I) -> ProtobufResult<()> {
+    let mut workspace_root = None;
+    let mut positional = Vec::new();
+    for arg in args {
+        let arg = arg.into_string().expect("arguments must be valid utf-8");
+        match arg.strip_prefix("--workspace_root=") {
+            Some(dir) => workspace_root = Some(std::path::PathBuf::from(dir)),
+            None if arg.starts_with("--") => panic!("unknown flag {}", arg),
+            None => positional.push(arg),
+        }
+    }
+    assert!(!positional.is_empty(), "crate name");
+    let crate_name = positional.remove(0);
+    let caches: Vec<std::path::PathBuf> = positional.into_iter().map(Into::into).collect();
+    let stdout = std::io::stdout();
+    let code = rustc_worker::check::check(
+        &crate_name,
+        &caches,
+        workspace_root.as_deref(),
+        &mut stdout.lock(),
+    )?;
+    std::process::exit(code);
+}
 
 fn main() -> ProtobufResult<()> {
     let mut args = std::env::args_os().peekable();
     // Always discard the executable name.
     args.next().unwrap();
 
+    match args.peek().and_then(|arg| arg.to_str()) {
+        Some("replay") => {
+            args.next();
+            return replay(args);
+        }
+        Some("decode") => {
+            args.next();
+            return decode(args);
+        }
+        Some("send") => {
+            args.next();
+            return send(args);
+        }
+        Some("cache") => {
+            args.next();
+            return cache(args);
+        }
+        Some("stats") => {
+            args.next();
+            return stats(args);
+        }
+        Some("rust-project") => {
+            args.next();
+            return rust_project(args);
+        }
+        Some("timings") => {
+            args.next();
+            return timings(args);
+        }
+        Some("graph") => {
+            args.next();
+            return graph(args);
+        }
+        Some("check") => {
+            args.next();
+            return check(args);
+        }
+        _ => {}
+    }
+
     let program = std::fs::canonicalize(args.next().expect("program name"))?;
     let rustc_path = std::fs::canonicalize(args.next().expect("rustc path"))?;
     let compilation_mode = args
@@ -12,8 +348,14 @@ fn main() -> ProtobufResult<()> {
         .expect("compilation mode")
         .into_string()
         .expect("compilation mode must be valid utf-8");
+    // Flags for the worker come before `--persistent_worker` or the response file.
+    let mut options = Options::default();
+    while let Some(flag) = next_worker_flag(&mut args) {
+        apply_flag(&mut options, &flag);
+    }
     // TODO: program and rustc_path will combine when this is merged into rules_rust.
-    let worker = rustc_worker::Worker::new(program, rustc_path, compilation_mode)?;
+    let worker =
+        rustc_worker::Worker::with_options(program, rustc_path, compilation_mode, options)?;
 
     // If started as a persistent worker.
     if let Some(arg) = args.peek() {
@@ -38,5 +380,5 @@ fn main() -> ProtobufResult<()> {
     assert!(response_file_arg.starts_with("@"));
     let response_file_path = &response_file_arg[1..];
     let status = worker.once_with_response_file(response_file_path)?;
-    std::process::exit(status.code().unwrap());
+    std::process::exit(rustc_worker::process_exit_code(status));
 }
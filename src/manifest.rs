@@ -0,0 +1,235 @@
+//! The `manifest.json` in each cache directory, describing what the cache holds.
+
+use crate::json;
+use crate::json::Value;
+use crate::lock;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const FILE_NAME: &str = "manifest.json";
+const LOCK_FILE_NAME: &str = "manifest.lock";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Manifest {
+    pub(crate) rustc: PathBuf,
+    /// The output of `rustc -vV` when the worker last started.
+    pub(crate) rustc_version: Option<String>,
+    pub(crate) compilation_mode: String,
+    /// The working directory of the worker, which Bazel sets to the execroot.
+    pub(crate) execroot: Option<PathBuf>,
+    /// Bazel's output base, which is part of the cache key.
+    pub(crate) output_base: Option<PathBuf>,
+    pub(crate) worker_version: String,
+    /// Seconds since the epoch.
+    pub(crate) created: u64,
+    /// When each crate was last compiled, in seconds since the epoch, by crate name.
+    pub(crate) crates: Vec<(String, u64)>,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The output of `rustc -vV`.
+pub(crate) fn rustc_version(rustc: &Path) -> Option<String> {
+    let output = std::process::Command::new(rustc).arg("-vV").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+impl Manifest {
+    pub(crate) fn load(cache: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(cache.join(FILE_NAME)).ok()?;
+        Self::from_json(&json::parse(&contents).ok()?)
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let string = |key| value.get(key).and_then(Value::as_str).map(String::from);
+        let crates = match value.get("crates") {
+            Some(Value::Object(entries)) => entries
+                .iter()
+                .filter_map(|(name, time)| Some((name.clone(), time.as_i64()? as u64)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(Manifest {
+            rustc: string("rustc")?.into(),
+            rustc_version: string("rustc_version"),
+            compilation_mode: string("compilation_mode")?,
+            execroot: string("execroot").map(PathBuf::from),
+            output_base: string("output_base").map(PathBuf::from),
+            worker_version: string("worker_version").unwrap_or_default(),
+            created: value.get("created").and_then(Value::as_i64).unwrap_or(0) as u64,
+            crates,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let crates = self
+            .crates
+            .iter()
+            .map(|(name, time)| (name.clone(), Value::from(*time)))
+            .collect();
+        Value::object()
+            .with("rustc", self.rustc.to_string_lossy().into_owned())
+            .with("rustc_version", self.rustc_version.clone())
+            .with("compilation_mode", self.compilation_mode.as_str())
+            .with(
+                "execroot",
+                self.execroot
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().into_owned()),
+            )
+            .with(
+                "output_base",
+                self.output_base
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().into_owned()),
+            )
+            .with("worker_version", self.worker_version.as_str())
+            .with("created", self.created)
+            .with("crates", Value::Object(crates))
+    }
+
+    fn save(&self, cache: &Path) -> io::Result<()> {
+        // Write and rename so readers never see a partial manifest.
+        let tmp = cache.join(format!("{}.{}", FILE_NAME, std::process::id()));
+        std::fs::write(&tmp, self.to_json().pretty() + "\n")?;
+        std::fs::rename(&tmp, cache.join(FILE_NAME))
+    }
+
+    /// The last time any crate was compiled with the cache.
+    pub(crate) fn last_used(&self) -> Option<u64> {
+        self.crates.iter().map(|(_, time)| *time).max()
+    }
+
+    pub(crate) fn crate_last_used(&self, crate_name: &str) -> Option<u64> {
+        self.crates
+            .iter()
+            .find(|(name, _)| name == crate_name)
+            .map(|(_, time)| *time)
+    }
+}
+
+/// Locks the manifest of a cache until the file is closed, so that workers sharing the cache
+/// don't lose each other's updates.
+fn lock(cache: &Path) -> io::Result<File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache.join(LOCK_FILE_NAME))?;
+    lock::lock_file(&file)?;
+    Ok(file)
+}
+
+/// Creates or refreshes the manifest when a worker starts, keeping the creation time and crate
+/// timestamps of an existing one.
+pub(crate) fn init(
+    cache: &Path,
+    rustc: &Path,
+    compilation_mode: &str,
+    execroot: &Path,
+    output_base: &Path,
+) -> io::Result<()> {
+    let _lock = lock(cache)?;
+    let existing = Manifest::load(cache).unwrap_or_default();
+    let manifest = Manifest {
+        rustc: rustc.to_owned(),
+        rustc_version: rustc_version(rustc),
+        compilation_mode: compilation_mode.to_string(),
+        execroot: Some(execroot.to_owned()),
+        output_base: Some(output_base.to_owned()),
+        worker_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: if existing.created == 0 {
+            now()
+        } else {
+            existing.created
+        },
+        crates: existing.crates,
+    };
+    manifest.save(cache)
+}
+
+/// Records that a crate was just compiled.
+pub(crate) fn touch_crate(cache: &Path, crate_name: &str) -> io::Result<()> {
+    let _lock = lock(cache)?;
+    let mut manifest = match Manifest::load(cache) {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+    let now = now();
+    match manifest
+        .crates
+        .iter_mut()
+        .find(|(name, _)| name == crate_name)
+    {
+        Some((_, time)) => *time = now,
+        None => manifest.crates.push((crate_name.to_string(), now)),
+    }
+    manifest.save(cache)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let manifest = Manifest {
+            rustc: "/usr/bin/rustc".into(),
+            rustc_version: Some("rustc 1.45.0\nhost: x86_64-unknown-linux-gnu\n".into()),
+            compilation_mode: "fastbuild".into(),
+            execroot: None,
+            output_base: Some("/output_base".into()),
+            worker_version: "0.1.0".into(),
+            created: 1_600_000_000,
+            crates: vec![("foo".into(), 1_600_000_100), ("bar".into(), 1_600_000_050)],
+        };
+        assert_eq!(
+            Manifest::from_json(&manifest.to_json()),
+            Some(manifest.clone())
+        );
+        assert_eq!(manifest.last_used(), Some(1_600_000_100));
+        assert_eq!(manifest.crate_last_used("bar"), Some(1_600_000_050));
+    }
+
+    #[test]
+    fn test_touch_crate() {
+        let cache =
+            std::env::temp_dir().join(format!("rustc-worker-manifest-test-{}", std::process::id()));
+        std::fs::create_dir_all(&cache).unwrap();
+        let execroot = Path::new("/output_base/execroot/__main__");
+        init(
+            &cache,
+            Path::new("rustc"),
+            "fastbuild",
+            execroot,
+            Path::new("/output_base"),
+        )
+        .unwrap();
+        // Workers sharing the cache touch their crates at the same time.
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let cache = cache.clone();
+                std::thread::spawn(move || touch_crate(&cache, &format!("crate{}", i)).unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let manifest = Manifest::load(&cache).unwrap();
+        std::fs::remove_dir_all(&cache).unwrap();
+        assert_eq!(manifest.execroot.as_deref(), Some(execroot));
+        assert_eq!(manifest.crates.len(), 8);
+    }
+}
@@ -0,0 +1,125 @@
+//! Conversions between the worker protocol messages and JSON.
+
+use crate::json::Value;
+use crate::worker_protocol::Input;
+use crate::worker_protocol::WorkRequest;
+use crate::worker_protocol::WorkResponse;
+use std::fmt::Write;
+
+pub(crate) fn request_to_json(request: &WorkRequest) -> Value {
+    let inputs: Vec<Value> = request
+        .get_inputs()
+        .iter()
+        .map(|input| {
+            Value::object()
+                .with("path", input.get_path())
+                .with("digest", to_hex(input.get_digest()))
+        })
+        .collect();
+    Value::object()
+        .with("arguments", request.get_arguments().to_vec())
+        .with("inputs", inputs)
+        .with("request_id", request.get_request_id())
+}
+
+pub(crate) fn request_from_json(value: &Value) -> Result<WorkRequest, String> {
+    let mut request = WorkRequest::default();
+    for argument in array(value, "arguments")? {
+        let argument = argument.as_str().ok_or("arguments must be strings")?;
+        request.mut_arguments().push(argument.to_string());
+    }
+    for input in array(value, "inputs")? {
+        let mut message = Input::default();
+        message.set_path(string(input, "path")?.to_string());
+        message.set_digest(from_hex(string(input, "digest")?)?);
+        request.mut_inputs().push(message);
+    }
+    request.set_request_id(number(value, "request_id")?);
+    Ok(request)
+}
+
+pub(crate) fn response_to_json(response: &WorkResponse) -> Value {
+    Value::object()
+        .with("exit_code", response.get_exit_code())
+        .with("output", response.get_output())
+        .with("request_id", response.get_request_id())
+}
+
+pub(crate) fn response_from_json(value: &Value) -> Result<WorkResponse, String> {
+    let mut response = WorkResponse::default();
+    response.set_exit_code(number(value, "exit_code")?);
+    response.set_output(string(value, "output")?.to_string());
+    response.set_request_id(number(value, "request_id")?);
+    Ok(response)
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("missing array `{}`", key))
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<&'a str, String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing string `{}`", key))
+}
+
+fn number(value: &Value, key: &str) -> Result<i32, String> {
+    value
+        .get(key)
+        .and_then(Value::as_i64)
+        .map(|n| n as i32)
+        .ok_or_else(|| format!("missing number `{}`", key))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 == 1 {
+        return Err(format!("odd length hex string {:?}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| format!("invalid hex string {:?}", hex))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let mut request = WorkRequest::default();
+        request.mut_arguments().push("--crate-name".to_string());
+        request.mut_arguments().push("foo".to_string());
+        let mut input = Input::default();
+        input.set_path("src/lib.rs".to_string());
+        input.set_digest(vec![0, 0xab, 0xff]);
+        request.mut_inputs().push(input);
+        request.set_request_id(7);
+
+        let value = request_to_json(&request);
+        assert_eq!(
+            value.get("inputs").unwrap().as_array().unwrap()[0]
+                .get("digest")
+                .unwrap()
+                .as_str(),
+            Some("00abff")
+        );
+        assert_eq!(request_from_json(&value), Ok(request));
+    }
+}
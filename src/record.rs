@@ -0,0 +1,228 @@
+//! Recording of the requests a worker handles, and replaying them without Bazel.
+//!
+//! The log is JSON, one entry per line, so that several worker processes can append to the
+//! same file. Every entry carries the time and the pid of the worker that wrote it, and each
+//! request the directory rustc ran in:
+//!
+//! ```text
+//! {"time_ms":...,"pid":...,"start":{"program":...,"rustc":...,"compilation_mode":...,"cwd":...}}
+//! {"time_ms":...,"pid":...,"request":{"arguments":[...],"inputs":[...],"request_id":0},"cwd":...}
+//! {"time_ms":...,"pid":...,"response":{"exit_code":0,"output":"...","request_id":0},"duration_ms":...}
+//! ```
+
+use crate::json;
+use crate::json::Value;
+use crate::lock::PrivateDir;
+use crate::protocol_json;
+use crate::worker_protocol::WorkRequest;
+use crate::worker_protocol::WorkResponse;
+use crate::Options;
+use crate::Worker;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub(crate) struct Recorder {
+    file: File,
+    pid: u32,
+    cwd: String,
+}
+
+impl Recorder {
+    /// Opens the log for appending and records the start of a worker session.
+    pub(crate) fn open(path: &Path, worker: &Worker) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut recorder = Recorder {
+            file,
+            pid: std::process::id(),
+            cwd: worker.cwd.to_string_lossy().into_owned(),
+        };
+        let start = Value::object()
+            .with(
+                "program",
+                worker.program_path.to_string_lossy().into_owned(),
+            )
+            .with("rustc", worker.rustc.to_string_lossy().into_owned())
+            .with("compilation_mode", worker.compilation_mode.as_str())
+            .with("cwd", recorder.cwd.as_str());
+        recorder.write(Value::object().with("start", start))?;
+        Ok(recorder)
+    }
+
+    pub(crate) fn request(&mut self, request: &WorkRequest) -> io::Result<()> {
+        self.write(
+            Value::object()
+                .with("request", protocol_json::request_to_json(request))
+                .with("cwd", self.cwd.as_str()),
+        )
+    }
+
+    pub(crate) fn response(
+        &mut self,
+        response: &WorkResponse,
+        duration: Duration,
+    ) -> io::Result<()> {
+        self.write(
+            Value::object()
+                .with("response", protocol_json::response_to_json(response))
+                .with("duration_ms", duration.as_millis() as u64),
+        )
+    }
+
+    fn write(&mut self, entry: Value) -> io::Result<()> {
+        let mut line = Value::object()
+            .with("time_ms", now_ms())
+            .with("pid", self.pid);
+        if let (Value::Object(line), Value::Object(entry)) = (&mut line, entry) {
+            line.extend(entry);
+        }
+        // A single write keeps lines from concurrent workers from interleaving.
+        self.file.write_all(format!("{}\n", line).as_bytes())
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn invalid_data<E: ToString>(line: usize, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, e.to_string()),
+    )
+}
+
+/// The directory a recorded request ran in, or the current one if it is not on this machine.
+fn replay_dir(recorded: &str) -> io::Result<PathBuf> {
+    let recorded = Path::new(recorded);
+    if recorded.is_dir() {
+        Ok(recorded.to_owned())
+    } else {
+        std::env::current_dir()
+    }
+}
+
+/// Feeds the requests in a log back through a worker per recorded session, and compares the
+/// responses with the recorded ones. Returns whether all of them matched.
+///
+/// Unless `options.cache_root` is given, the workers start with empty caches in a private
+/// directory that is removed afterwards, so replaying leaves the caches of real workers alone.
+pub fn replay<W: io::Write>(log: &Path, mut options: Options, out: &mut W) -> io::Result<bool> {
+    let scratch = match options.cache_root {
+        Some(_) => None,
+        None => Some(PrivateDir::create()?),
+    };
+    if let Some(scratch) = &scratch {
+        options.cache_root = Some(scratch.path().to_owned());
+    }
+    let reader = io::BufReader::new(File::open(log)?);
+    let mut workers: HashMap<i64, Worker> = HashMap::new();
+    let mut pending: HashMap<(i64, i32), VecDeque<(WorkResponse, Duration)>> = HashMap::new();
+    let mut all_matched = true;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = i + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = json::parse(&line).map_err(|e| invalid_data(number, e))?;
+        let pid = entry
+            .get("pid")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| invalid_data(number, "missing pid"))?;
+
+        if let Some(start) = entry.get("start") {
+            let field = |key| {
+                start
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid_data(number, format!("missing {}", key)))
+            };
+            let worker = Worker::in_dir(
+                field("program")?.into(),
+                field("rustc")?.into(),
+                field("compilation_mode")?,
+                options.clone(),
+                replay_dir(field("cwd")?)?,
+            )?;
+            workers.insert(pid, worker);
+        } else if let Some(request) = entry.get("request") {
+            let request =
+                protocol_json::request_from_json(request).map_err(|e| invalid_data(number, e))?;
+            let worker = workers
+                .get(&pid)
+                .ok_or_else(|| invalid_data(number, "request before worker start"))?;
+            // Logs from before requests had a directory ran them all where the worker started.
+            let cwd = match entry.get("cwd").and_then(Value::as_str) {
+                Some(cwd) => replay_dir(cwd)?,
+                None => worker.cwd.clone(),
+            };
+            let key = (pid, request.get_request_id());
+            let start = Instant::now();
+            let response = worker
+                .handle_request(request, &cwd)
+                .map_err(|e| invalid_data(number, e))?;
+            pending
+                .entry(key)
+                .or_default()
+                .push_back((response, start.elapsed()));
+        } else if let Some(recorded) = entry.get("response") {
+            let recorded =
+                protocol_json::response_from_json(recorded).map_err(|e| invalid_data(number, e))?;
+            let recorded_ms = entry
+                .get("duration_ms")
+                .and_then(Value::as_i64)
+                .unwrap_or(0);
+            let key = (pid, recorded.get_request_id());
+            let (replayed, duration) = match pending.get_mut(&key).and_then(VecDeque::pop_front) {
+                Some(replayed) => replayed,
+                None => {
+                    writeln!(out, "line {}: response without a request", number)?;
+                    all_matched = false;
+                    continue;
+                }
+            };
+            let matched = recorded.get_exit_code() == replayed.get_exit_code()
+                && recorded.get_output() == replayed.get_output();
+            all_matched &= matched;
+            writeln!(
+                out,
+                "pid {} request {}: {} in {}ms (recorded {}ms)",
+                pid,
+                key.1,
+                if matched { "ok" } else { "MISMATCH" },
+                duration.as_millis(),
+                recorded_ms
+            )?;
+            if !matched {
+                writeln!(
+                    out,
+                    "  recorded exit code {}, output:\n{}",
+                    recorded.get_exit_code(),
+                    recorded.get_output()
+                )?;
+                writeln!(
+                    out,
+                    "  replayed exit code {}, output:\n{}",
+                    replayed.get_exit_code(),
+                    replayed.get_output()
+                )?;
+            }
+        }
+    }
+    Ok(all_matched)
+}
@@ -0,0 +1,297 @@
+//! Generates a `rust-project.json` for rust-analyzer from the invocations the worker recorded,
+//! so the IDE sees the crates, editions, cfgs and dependencies of the actual Bazel build.
+
+use crate::cache;
+use crate::invocations;
+use crate::invocations::Invocation;
+use crate::json::Value;
+use crate::manifest::Manifest;
+use crate::rustc_args::RustcArgs;
+use crate::unused_deps;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// An invocation with what is needed to place its files.
+struct Crate {
+    invocation: Invocation,
+    output_base: Option<PathBuf>,
+}
+
+impl Crate {
+    /// Where a file rustc was given lives. Sources of the main repository are taken from the
+    /// workspace when one is given, and sources of external repositories from the output base,
+    /// rather than through the symlinks in the execroot.
+    fn path(&self, path: &str, workspace_root: Option<&Path>) -> PathBuf {
+        let relative = Path::new(path);
+        if relative.is_absolute() {
+            return relative.to_owned();
+        }
+        if relative.starts_with("external") {
+            return match &self.output_base {
+                Some(output_base) => output_base.join(relative),
+                None => self.invocation.resolve(path),
+            };
+        }
+        match workspace_root {
+            Some(workspace_root) if !relative.starts_with("bazel-out") => {
+                workspace_root.join(relative)
+            }
+            _ => self.invocation.resolve(path),
+        }
+    }
+}
+
+/// The sysroot of the toolchain, and the standard library sources in it if they are installed.
+fn sysroot(rustc: &Path) -> Option<(PathBuf, Option<PathBuf>)> {
+    let output = std::process::Command::new(rustc)
+        .args(&["--print", "sysroot"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let sysroot = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    let src = sysroot.join("lib/rustlib/src/rust/library");
+    let src = if src.is_dir() { Some(src) } else { None };
+    Some((sysroot, src))
+}
+
+fn crate_json(krate: &Crate, root: &str, deps: Vec<Value>, workspace_root: Option<&Path>) -> Value {
+    let args = RustcArgs::new(&krate.invocation.args);
+    let mut cfg: Vec<String> = args.values("--cfg").into_iter().map(String::from).collect();
+    if args.has("--test") {
+        cfg.push("test".to_string());
+    }
+    let is_proc_macro = args.values("--crate-type").contains(&"proc-macro");
+    let mut value = Value::object()
+        .with("display_name", krate.invocation.crate_name.as_str())
+        .with(
+            "root_module",
+            krate
+                .path(root, workspace_root)
+                .to_string_lossy()
+                .into_owned(),
+        )
+        .with("edition", args.value("--edition").unwrap_or("2015"))
+        .with("deps", deps)
+        .with("cfg", cfg)
+        .with(
+            "is_workspace_member",
+            !Path::new(root).starts_with("external"),
+        )
+        .with(
+            "env",
+            Value::Object(
+                krate
+                    .invocation
+                    .env
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                    .collect(),
+            ),
+        )
+        .with("is_proc_macro", is_proc_macro);
+    if is_proc_macro {
+        // Only the library the proc macro was actually built as, for this host.
+        let dylib = krate
+            .invocation
+            .outputs()
+            .into_iter()
+            .filter(|output| {
+                output
+                    .extension()
+                    .map_or(false, |e| e == std::env::consts::DLL_EXTENSION)
+            })
+            .map(|output| krate.invocation.resolve(&output.to_string_lossy()))
+            .find(|dylib| dylib.exists());
+        if let Some(dylib) = dylib {
+            value = value.with(
+                "proc_macro_dylib_path",
+                dylib.to_string_lossy().into_owned(),
+            );
+        }
+    }
+    value
+}
+
+/// Writes a `rust-project.json` covering the crates compiled with the given caches, or every
+/// cache when none are given. When a crate was compiled several times with different outputs,
+/// as in different compilation modes, the latest compilation is used.
+pub fn generate<W: io::Write>(
+    caches: &[PathBuf],
+    workspace_root: Option<&Path>,
+    out: &mut W,
+) -> io::Result<()> {
+    let caches = if caches.is_empty() {
+        cache::discover()?
+    } else {
+        caches.to_vec()
+    };
+    let mut crates = Vec::new();
+    let mut rustc = None;
+    for cache in &caches {
+        let manifest = Manifest::load(cache);
+        if rustc.is_none() {
+            rustc = manifest.as_ref().map(|manifest| manifest.rustc.clone());
+        }
+        for invocation in invocations::load(cache)? {
+            crates.push(Crate {
+                invocation,
+                output_base: manifest.as_ref().and_then(|m| m.output_base.clone()),
+            });
+        }
+    }
+    // Newest first, so the latest compilation of a crate is the one kept.
+    crates.sort_by_key(|krate| std::cmp::Reverse(krate.invocation.time));
+
+    // Each crate is identified by its name, root and cfgs, and every output of every compilation
+    // of it points at it.
+    let mut kept: Vec<(&Crate, &str)> = Vec::new();
+    let mut ids: HashMap<(String, PathBuf, Vec<&str>, bool), usize> = HashMap::new();
+    let mut by_output: HashMap<PathBuf, usize> = HashMap::new();
+    for krate in &crates {
+        let args = RustcArgs::new(&krate.invocation.args);
+        let root = match args.source() {
+            Some(root) => root,
+            None => continue,
+        };
+        let id = (
+            krate.invocation.crate_name.clone(),
+            krate.path(root, workspace_root),
+            args.values("--cfg"),
+            args.has("--test"),
+        );
+        let index = *ids.entry(id).or_insert_with(|| {
+            kept.push((krate, root));
+            kept.len() - 1
+        });
+        for output in krate.invocation.outputs() {
+            by_output.entry(output).or_insert(index);
+        }
+    }
+
+    let mut crates_json = Vec::new();
+    for (krate, root) in &kept {
+        let args = RustcArgs::new(&krate.invocation.args);
+        let deps: Vec<Value> = unused_deps::externs(&args)
+            .into_iter()
+            .filter_map(|(name, path)| {
+                let index = by_output.get(Path::new(path?))?;
+                Some(Value::object().with("crate", *index).with("name", name))
+            })
+            .collect();
+        crates_json.push(crate_json(krate, root, deps, workspace_root));
+    }
+    let mut project = Value::object();
+    if let Some((sysroot, src)) = rustc.as_deref().and_then(sysroot) {
+        project = project.with("sysroot", sysroot.to_string_lossy().into_owned());
+        if let Some(src) = src {
+            project = project.with("sysroot_src", src.to_string_lossy().into_owned());
+        }
+    }
+    project = project.with("crates", crates_json);
+    writeln!(out, "{}", project.pretty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn test_generate() {
+        let cache = std::env::temp_dir().join(format!(
+            "rustc-worker-rust-project-test-{}",
+            std::process::id()
+        ));
+        let record = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            let execroot = Path::new("/execroot");
+            let duration = std::time::Duration::from_millis(1);
+            invocations::record(&cache, execroot, &args, duration).unwrap();
+        };
+        record(&[
+            "--crate-name=dep",
+            "--crate-type=rlib",
+            "--edition=2018",
+            "--cfg",
+            "feature=\"std\"",
+            "--out-dir=bazel-out/bin/dep",
+            "external/dep/src/lib.rs",
+        ]);
+        record(&[
+            "--crate-name=app",
+            "--crate-type=bin",
+            "--edition=2021",
+            "--extern=dep=bazel-out/bin/dep/libdep.rlib",
+            "--out-dir=bazel-out/bin/app",
+            "app/main.rs",
+        ]);
+
+        let mut out = Vec::new();
+        let workspace = Path::new("/workspace");
+        generate(std::slice::from_ref(&cache), Some(workspace), &mut out).unwrap();
+        std::fs::remove_dir_all(&cache).unwrap();
+        let project = json::parse(std::str::from_utf8(&out).unwrap()).unwrap();
+        let crates = project.get("crates").and_then(Value::as_array).unwrap();
+        assert_eq!(crates.len(), 2);
+        let get = |krate: &Value, key| krate.get(key).and_then(Value::as_str).map(String::from);
+        let (app, dep) = (&crates[0], &crates[1]);
+        assert_eq!(get(app, "display_name").unwrap(), "app");
+        assert_eq!(get(app, "root_module").unwrap(), "/workspace/app/main.rs");
+        assert_eq!(get(app, "edition").unwrap(), "2021");
+        assert_eq!(
+            app.get("deps"),
+            Some(&Value::Array(vec![Value::object()
+                .with("crate", 1)
+                .with("name", "dep")]))
+        );
+        assert_eq!(
+            get(dep, "root_module").unwrap(),
+            "/execroot/external/dep/src/lib.rs"
+        );
+        assert_eq!(dep.get("cfg"), Some(&Value::from(vec!["feature=\"std\""])));
+        assert_eq!(dep.get("is_workspace_member"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn test_proc_macro_dylib() {
+        let out_dir = std::env::temp_dir().join(format!(
+            "rustc-worker-proc-macro-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let args = [
+            "--crate-name=derive",
+            "--crate-type=proc-macro",
+            &format!("--out-dir={}", out_dir.display()),
+            "derive/lib.rs",
+        ];
+        let krate = Crate {
+            invocation: Invocation {
+                time: 0,
+                crate_name: "derive".to_string(),
+                cwd: PathBuf::from("/execroot"),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                env: Vec::new(),
+                duration: None,
+            },
+            output_base: None,
+        };
+        let dylib = || {
+            crate_json(&krate, "derive/lib.rs", Vec::new(), None)
+                .get("proc_macro_dylib_path")
+                .and_then(Value::as_str)
+                .map(PathBuf::from)
+        };
+        // Not built yet.
+        assert_eq!(dylib(), None);
+        let built = out_dir.join(format!("libderive.{}", std::env::consts::DLL_EXTENSION));
+        std::fs::write(&built, "").unwrap();
+        let found = dylib();
+        std::fs::remove_dir_all(&out_dir).unwrap();
+        assert_eq!(found, Some(built));
+    }
+}
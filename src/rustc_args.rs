@@ -0,0 +1,377 @@
+//! A read-only view over the rustc command line of a work request.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) struct RustcArgs<'a> {
+    args: &'a [String],
+}
+
+impl<'a> RustcArgs<'a> {
+    pub(crate) fn new(args: &'a [String]) -> Self {
+        RustcArgs { args }
+    }
+
+    /// All values of a flag, accepting both `--flag value` and `--flag=value`, and `-Fvalue` for
+    /// single letter flags.
+    pub(crate) fn values(&self, flag: &str) -> Vec<&'a str> {
+        let short = !flag.starts_with("--");
+        let mut values = Vec::new();
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == flag {
+                if let Some(value) = iter.next() {
+                    values.push(value.as_str());
+                }
+            } else if let Some(value) = arg.strip_prefix(flag) {
+                if short {
+                    values.push(value);
+                } else if let Some(value) = value.strip_prefix('=') {
+                    values.push(value);
+                }
+            }
+        }
+        values
+    }
+
+    /// The last value of a flag, which is the one rustc uses.
+    pub(crate) fn value(&self, flag: &str) -> Option<&'a str> {
+        self.values(flag).pop()
+    }
+
+    /// The last value of a `-C key=value` codegen option.
+    pub(crate) fn codegen(&self, key: &str) -> Option<&'a str> {
+        let mut found = None;
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            let option = if arg == "-C" || arg == "--codegen" {
+                match iter.next() {
+                    Some(option) => option.as_str(),
+                    None => break,
+                }
+            } else if let Some(option) = arg.strip_prefix("--codegen=") {
+                option
+            } else if let Some(option) = arg.strip_prefix("-C") {
+                // `-Ckey=value` has no separator between the flag and the option.
+                option
+            } else {
+                continue;
+            };
+            if let Some(value) = option
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('='))
+            {
+                found = Some(value);
+            }
+        }
+        found
+    }
+
+    /// Whether a flag without a value, like `--test`, is given.
+    pub(crate) fn has(&self, flag: &str) -> bool {
+        self.args.iter().any(|arg| arg == flag)
+    }
+
+    /// The command line without a flag that takes a value, in any of the forms `values`
+    /// accepts.
+    pub(crate) fn without(&self, flag: &str) -> Vec<String> {
+        let prefix = if flag.starts_with("--") {
+            format!("{}=", flag)
+        } else {
+            flag.to_string()
+        };
+        let mut args = Vec::new();
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == flag {
+                iter.next();
+            } else if !arg.starts_with(&prefix) {
+                args.push(arg.clone());
+            }
+        }
+        args
+    }
+
+    /// The crate root, the one source file on the command line.
+    pub(crate) fn source(&self) -> Option<&'a str> {
+        self.args
+            .iter()
+            .rev()
+            .find(|arg| !arg.starts_with('-') && arg.ends_with(".rs"))
+            .map(String::as_str)
+    }
+
+    pub(crate) fn crate_name(&self) -> Option<&'a str> {
+        self.value("--crate-name")
+    }
+
+    /// A name for files about the crate that is unique in its output directory: the crate name
+    /// with its `extra-filename`, and `.test` for a test harness, which can share both with the
+    /// library it tests.
+    pub(crate) fn file_stem(&self) -> Option<String> {
+        let mut stem = format!(
+            "{}{}",
+            self.crate_name()?,
+            self.codegen("extra-filename").unwrap_or("")
+        );
+        if self.has("--test") {
+            stem.push_str(".test");
+        }
+        Some(stem)
+    }
+
+    pub(crate) fn out_dir(&self) -> Option<PathBuf> {
+        self.value("--out-dir").map(PathBuf::from)
+    }
+
+    /// Every output kind requested through `--emit`, with its explicit path if any.
+    pub(crate) fn emit(&self) -> Vec<(&'a str, Option<&'a str>)> {
+        let mut emit = Vec::new();
+        for value in self.values("--emit") {
+            for kind in value.split(',') {
+                let mut parts = kind.splitn(2, '=');
+                let name = parts.next().unwrap();
+                emit.push((name, parts.next()));
+            }
+        }
+        emit
+    }
+}
+
+/// A command line with its outputs moved elsewhere.
+pub(crate) struct Redirected {
+    pub(crate) args: Vec<String>,
+    /// Each moved output, a file or the output directory, with where it would have gone.
+    pub(crate) outputs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Redirected {
+    /// Moves one output into `scratch`, returning the new path.
+    fn output(&mut self, scratch: &Path, original: &str, directory: bool) -> String {
+        let original = PathBuf::from(original);
+        let mut moved = scratch.join(self.outputs.len().to_string());
+        if !directory {
+            moved.push(original.file_name().unwrap_or_default());
+        }
+        self.outputs.push((moved.clone(), original));
+        moved.to_string_lossy().into_owned()
+    }
+
+    fn emit(&mut self, scratch: &Path, value: &str) -> String {
+        let kinds: Vec<String> = value
+            .split(',')
+            .map(|kind| {
+                let mut parts = kind.splitn(2, '=');
+                let name = parts.next().unwrap();
+                match parts.next() {
+                    Some(path) => format!("{}={}", name, self.output(scratch, path, false)),
+                    None => kind.to_string(),
+                }
+            })
+            .collect();
+        kinds.join(",")
+    }
+}
+
+impl<'a> RustcArgs<'a> {
+    /// The command line with `--out-dir`, `-o` and explicit `--emit` paths moved into
+    /// `scratch`. `None` if rustc would write outputs to the working directory, where they
+    /// can't be told apart from everything else.
+    pub(crate) fn redirect_outputs(&self, scratch: &Path) -> Option<Redirected> {
+        let mut redirected = Redirected {
+            args: Vec::new(),
+            outputs: Vec::new(),
+        };
+        let mut has_destination = false;
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            let arg = arg.as_str();
+            let moved = if arg == "--out-dir" || arg == "-o" || arg == "--emit" {
+                redirected.args.push(arg.to_string());
+                let value = match iter.next() {
+                    Some(value) => value,
+                    None => break,
+                };
+                match arg {
+                    "--emit" => redirected.emit(scratch, value),
+                    _ => {
+                        has_destination = true;
+                        redirected.output(scratch, value, arg == "--out-dir")
+                    }
+                }
+            } else if let Some(value) = arg.strip_prefix("--out-dir=") {
+                has_destination = true;
+                format!("--out-dir={}", redirected.output(scratch, value, true))
+            } else if let Some(value) = arg.strip_prefix("--emit=") {
+                format!("--emit={}", redirected.emit(scratch, value))
+            } else if let Some(value) = arg.strip_prefix("-o") {
+                // `-oFILE`, which no other flag starts with.
+                has_destination = true;
+                format!("-o{}", redirected.output(scratch, value, false))
+            } else {
+                arg.to_string()
+            };
+            redirected.args.push(moved);
+        }
+        if has_destination {
+            Some(redirected)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RustcArgs;
+    use std::path::Path;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_values() {
+        let args = args(&[
+            "--crate-name",
+            "foo",
+            "--out-dir=bazel-out/bin",
+            "src/lib.rs",
+        ]);
+        let args = RustcArgs::new(&args);
+        assert_eq!(args.crate_name(), Some("foo"));
+        assert_eq!(args.out_dir(), Some("bazel-out/bin".into()));
+        assert_eq!(args.value("--edition"), None);
+
+        let args = self::args(&["-Wunused", "-W", "dead-code", "--warn=missing-docs"]);
+        let args = RustcArgs::new(&args);
+        assert_eq!(args.values("-W"), ["unused", "dead-code"]);
+        assert_eq!(args.values("--warn"), ["missing-docs"]);
+    }
+
+    #[test]
+    fn test_codegen() {
+        let args = args(&[
+            "--codegen",
+            "extra-filename=-1",
+            "-Copt-level=3",
+            "-C",
+            "extra-filename=-2",
+        ]);
+        let args = RustcArgs::new(&args);
+        assert_eq!(args.codegen("extra-filename"), Some("-2"));
+        assert_eq!(args.codegen("opt-level"), Some("3"));
+        assert_eq!(args.codegen("debuginfo"), None);
+    }
+
+    #[test]
+    fn test_file_stem() {
+        let args = args(&["--crate-name=foo", "-Cextra-filename=-1a2b"]);
+        assert_eq!(RustcArgs::new(&args).file_stem().unwrap(), "foo-1a2b");
+        let args = self::args(&["--crate-name=foo", "--test"]);
+        assert_eq!(RustcArgs::new(&args).file_stem().unwrap(), "foo.test");
+        assert_eq!(RustcArgs::new(&[]).file_stem(), None);
+    }
+
+    #[test]
+    fn test_redirect_outputs() {
+        let args = args(&[
+            "--crate-name",
+            "foo",
+            "--out-dir=bazel-out/bin",
+            "--emit=dep-info,link,metadata=bazel-out/bin/libfoo.rmeta",
+            "src/lib.rs",
+        ]);
+        let redirected = RustcArgs::new(&args)
+            .redirect_outputs(Path::new("/scratch"))
+            .unwrap();
+        assert_eq!(
+            redirected.args,
+            vec![
+                "--crate-name",
+                "foo",
+                "--out-dir=/scratch/0",
+                "--emit=dep-info,link,metadata=/scratch/1/libfoo.rmeta",
+                "src/lib.rs",
+            ]
+        );
+        assert_eq!(
+            redirected.outputs,
+            vec![
+                ("/scratch/0".into(), "bazel-out/bin".into()),
+                (
+                    "/scratch/1/libfoo.rmeta".into(),
+                    "bazel-out/bin/libfoo.rmeta".into()
+                ),
+            ]
+        );
+        assert!(RustcArgs::new(&args[..2])
+            .redirect_outputs(Path::new("/scratch"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_redirect_joined_output() {
+        let args = args(&["--crate-name=foo", "-obazel-out/bin/libfoo.rlib"]);
+        let redirected = RustcArgs::new(&args)
+            .redirect_outputs(Path::new("/scratch"))
+            .unwrap();
+        assert_eq!(
+            redirected.args,
+            vec!["--crate-name=foo", "-o/scratch/0/libfoo.rlib"]
+        );
+        assert_eq!(
+            redirected.outputs,
+            vec![(
+                "/scratch/0/libfoo.rlib".into(),
+                "bazel-out/bin/libfoo.rlib".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_without() {
+        let args = args(&[
+            "-o",
+            "a",
+            "-ob",
+            "--out-dir=c",
+            "--emit",
+            "link",
+            "--emit=metadata",
+            "--error-format=json",
+        ]);
+        let args = RustcArgs::new(&args);
+        assert_eq!(
+            args.without("-o"),
+            [
+                "--out-dir=c",
+                "--emit",
+                "link",
+                "--emit=metadata",
+                "--error-format=json"
+            ]
+        );
+        assert_eq!(
+            args.without("--emit"),
+            ["-o", "a", "-ob", "--out-dir=c", "--error-format=json"]
+        );
+    }
+
+    #[test]
+    fn test_emit() {
+        let args = args(&[
+            "--emit=dep-info,link",
+            "--emit",
+            "metadata=out/libfoo.rmeta",
+        ]);
+        assert_eq!(
+            RustcArgs::new(&args).emit(),
+            vec![
+                ("dep-info", None),
+                ("link", None),
+                ("metadata", Some("out/libfoo.rmeta"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! An experiment that compiles a fraction of requests without the shared incremental cache, so
+//! the history shows what the cache saves for each crate.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+static SAMPLE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How sampled requests are compiled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sample {
+    /// Without `--codegen incremental` at all.
+    Off,
+    /// With an empty incremental directory of their own.
+    Cold,
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+        Sample::Off
+    }
+}
+
+impl std::str::FromStr for Sample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Sample::Off),
+            "cold" => Ok(Sample::Cold),
+            _ => Err(format!("unknown sample mode {:?}", s)),
+        }
+    }
+}
+
+/// Decides at random whether to sample a request, with the given probability.
+pub(crate) fn sampled(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    // RandomState is seeded randomly, which is all the randomness this needs.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(SAMPLE_COUNTER.fetch_add(1, Ordering::Relaxed));
+    (hasher.finish() as f64 / u64::MAX as f64) < rate
+}
+
+#[cfg(test)]
+mod test {
+    use super::sampled;
+
+    #[test]
+    fn test_sampled() {
+        assert!(!(0..100).any(|_| sampled(0.0)));
+        assert!((0..100).all(|_| sampled(1.0)));
+        let count = (0..10_000).filter(|_| sampled(0.25)).count();
+        assert!((2000..3000).contains(&count), "{}", count);
+    }
+}
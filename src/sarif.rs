@@ -0,0 +1,252 @@
+//! SARIF 2.1.0 reports of the errors and warnings rustc reported, one per crate, for code
+//! scanning tools.
+//!
+//! Each report is written to `<dir>/<package>/<crate><extra-filename>.sarif` from rustc's JSON
+//! diagnostics, with `.test` before `.sarif` for test harnesses.
+//! Diagnostics without a location, like "aborting due to 2 previous errors", are left out.
+
+use crate::json::Value;
+use crate::locations::Locations;
+use crate::rustc_args::RustcArgs;
+use crate::unused_deps;
+use std::io;
+use std::path::Path;
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// The SARIF level of a rustc diagnostic level.
+fn level(level: &str) -> &'static str {
+    match level {
+        "warning" => "warning",
+        "note" | "help" | "failure-note" => "note",
+        // Including "error: internal compiler error".
+        _ => "error",
+    }
+}
+
+/// The region of a span, or `None` if it has no line numbers.
+fn region(span: &Value) -> Option<Value> {
+    let number = |key| span.get(key).and_then(Value::as_i64);
+    let start_line = number("line_start")?;
+    Some(
+        Value::object()
+            .with("startLine", start_line)
+            .with_some("startColumn", number("column_start"))
+            .with_some("endLine", number("line_end"))
+            .with_some("endColumn", number("column_end")),
+    )
+}
+
+/// A SARIF result for a diagnostic, located at its primary spans.
+fn result(diagnostic: &Value, locations: Option<&Locations>) -> Option<Value> {
+    let spans: Vec<&Value> = diagnostic
+        .get("spans")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter(|span| span.get("is_primary") == Some(&Value::Bool(true)))
+        .collect();
+    if spans.is_empty() {
+        return None;
+    }
+    let physical_locations: Vec<Value> = spans
+        .iter()
+        .filter_map(|span| {
+            let file_name = span.get("file_name").and_then(Value::as_str)?;
+            let uri = locations
+                .and_then(|locations| locations.rewrite_path(file_name))
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|| file_name.to_string());
+            Some(
+                Value::object().with(
+                    "physicalLocation",
+                    Value::object()
+                        .with("artifactLocation", Value::object().with("uri", uri))
+                        .with_some("region", region(span)),
+                ),
+            )
+        })
+        .collect();
+    let message = diagnostic.get("message").and_then(Value::as_str)?;
+    let rule_id = diagnostic
+        .get("code")
+        .and_then(|code| code.get("code"))
+        .and_then(Value::as_str);
+    let level = diagnostic
+        .get("level")
+        .and_then(Value::as_str)
+        .unwrap_or("error");
+    Some(
+        Value::object()
+            .with_some("ruleId", rule_id)
+            .with("level", self::level(level))
+            .with("message", Value::object().with("text", message))
+            .with("locations", physical_locations),
+    )
+}
+
+/// The rules the diagnostics refer to, with the explanation of error codes.
+fn rules(diagnostics: &[Value]) -> Vec<Value> {
+    let mut rules: Vec<(&str, Option<&str>)> = Vec::new();
+    for code in diagnostics.iter().filter_map(|d| d.get("code")) {
+        if let Some(id) = code.get("code").and_then(Value::as_str) {
+            if !rules.iter().any(|(known, _)| *known == id) {
+                rules.push((id, code.get("explanation").and_then(Value::as_str)));
+            }
+        }
+    }
+    rules
+        .into_iter()
+        .map(|(id, explanation)| {
+            let rule = Value::object().with("id", id);
+            match explanation {
+                Some(text) => rule.with("fullDescription", Value::object().with("text", text)),
+                None => rule,
+            }
+        })
+        .collect()
+}
+
+/// The SARIF log for one crate.
+fn report(args: &RustcArgs, diagnostics: &[Value], locations: Option<&Locations>) -> Value {
+    let crate_name = args.crate_name().unwrap_or("unknown");
+    let stem = args.file_stem().unwrap_or_else(|| "unknown".to_string());
+    // Ending in "/", the whole id is the category that code scanning keeps the results of one run
+    // under, so a test harness doesn't replace its library's.
+    let id = args
+        .out_dir()
+        .as_deref()
+        .and_then(unused_deps::package_dir)
+        .map(|package| format!("{}/{}/", unused_deps::label(&package, crate_name), stem));
+    let driver = Value::object()
+        .with("name", "rustc")
+        .with("informationUri", "https://www.rust-lang.org")
+        .with("rules", rules(diagnostics));
+    let results: Vec<Value> = diagnostics
+        .iter()
+        .filter_map(|diagnostic| result(diagnostic, locations))
+        .collect();
+    let run = Value::object()
+        .with("tool", Value::object().with("driver", driver))
+        .with("results", results)
+        .with_some(
+            "automationDetails",
+            id.map(|id| Value::object().with("id", id)),
+        );
+    Value::object()
+        .with("$schema", SCHEMA)
+        .with("version", "2.1.0")
+        .with("runs", vec![run])
+}
+
+/// Writes the report for one crate to `<dir>/<package>/<file stem>.sarif`.
+pub(crate) fn write(
+    dir: &Path,
+    args: &RustcArgs,
+    diagnostics: &[Value],
+    locations: Option<&Locations>,
+) -> io::Result<()> {
+    let package = args.out_dir().as_deref().and_then(unused_deps::package_dir);
+    let mut path = dir.join(package.unwrap_or_default());
+    std::fs::create_dir_all(&path)?;
+    let stem = args.file_stem().unwrap_or_else(|| "unknown".to_string());
+    path.push(format!("{}.sarif", stem));
+    std::fs::write(&path, report(args, diagnostics, locations).pretty() + "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn test_report() {
+        let diagnostics = [
+            r#"{"message":"cannot find value `x` in this scope","code":{"code":"E0425","explanation":"An unresolved name was used."},"level":"error","spans":[{"file_name":"src/lib.rs","line_start":1,"line_end":1,"column_start":32,"column_end":33,"is_primary":true}]}"#,
+            r#"{"message":"aborting due to 1 previous error","code":null,"level":"error","spans":[]}"#,
+        ]
+        .iter()
+        .map(|d| json::parse(d).unwrap())
+        .collect::<Vec<_>>();
+        let args = vec![
+            "--crate-name".to_string(),
+            "foo".to_string(),
+            "--out-dir=bazel-out/k8-fastbuild/bin/foo".to_string(),
+        ];
+        let report = report(&RustcArgs::new(&args), &diagnostics, None);
+        assert_eq!(
+            report.to_string(),
+            concat!(
+                r#"{"$schema":"https://json.schemastore.org/sarif-2.1.0.json","version":"2.1.0","#,
+                r#""runs":[{"tool":{"driver":{"name":"rustc","#,
+                r#""informationUri":"https://www.rust-lang.org","#,
+                r#""rules":[{"id":"E0425","fullDescription":{"text":"An unresolved name was used."}}]}},"#,
+                r#""results":[{"ruleId":"E0425","level":"error","#,
+                r#""message":{"text":"cannot find value `x` in this scope"},"#,
+                r#""locations":[{"physicalLocation":{"artifactLocation":{"uri":"src/lib.rs"},"#,
+                r#""region":{"startLine":1,"startColumn":32,"endLine":1,"endColumn":33}}}]}],"#,
+                r#""automationDetails":{"id":"//foo:foo/foo/"}}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_result() {
+        let diagnostic = json::parse(
+            r#"{"message":"unused variable: `x`","code":null,"level":"warning","spans":[{"file_name":"src/lib.rs","line_start":1,"line_end":null,"is_primary":true}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            result(&diagnostic, None).unwrap().to_string(),
+            concat!(
+                r#"{"level":"warning","message":{"text":"unused variable: `x`"},"#,
+                r#""locations":[{"physicalLocation":{"artifactLocation":{"uri":"src/lib.rs"},"#,
+                r#""region":{"startLine":1}}}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_automation_details() {
+        let id = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            let report = report(&RustcArgs::new(&args), &[], None);
+            let runs = report.get("runs").and_then(Value::as_array).unwrap();
+            let details = runs[0].get("automationDetails").unwrap();
+            details
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap()
+                .to_string()
+        };
+        let out_dir = "--out-dir=bazel-out/k8-fastbuild/bin/foo";
+        assert_eq!(id(&["--crate-name=foo", out_dir]), "//foo:foo/foo/");
+        assert_eq!(
+            id(&["--crate-name=foo", out_dir, "--test"]),
+            "//foo:foo/foo.test/"
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let dir =
+            std::env::temp_dir().join(format!("rustc-worker-sarif-test-{}", std::process::id()));
+        let library = [
+            "--crate-name=foo",
+            "--out-dir=bazel-out/k8-fastbuild/bin/foo",
+        ];
+        let test = [
+            "--crate-name=foo",
+            "--out-dir=bazel-out/k8-fastbuild/bin/foo",
+            "--test",
+        ];
+        for args in [&library[..], &test[..]].iter() {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            write(&dir, &RustcArgs::new(&args), &[], None).unwrap();
+        }
+        let written = |name| dir.join("foo").join(name).is_file();
+        let both = written("foo.sarif") && written("foo.test.sarif");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(both);
+    }
+}
@@ -0,0 +1,117 @@
+//! Per-request resource usage, written to a stats file and shown in verbose responses.
+//!
+//! The stats file is JSON, one entry per line, so that several workers can append to it:
+//!
+//! ```text
+//! {"time_ms":...,"pid":...,"request_id":0,"crate":"foo","exit_code":0,"wall_ms":...,
+//!  "user_ms":...,"system_ms":...,"max_rss_bytes":...,"block_in":...,"block_out":...}
+//! ```
+
+use crate::cache;
+use crate::child::Usage;
+use crate::json::Value;
+use crate::record;
+use crate::worker_protocol::WorkRequest;
+use protobuf::Message;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// The verbosity Bazel sets with `--worker_verbose`, at or above which responses include the
+/// resource usage of rustc.
+pub(crate) const VERBOSE: u64 = 10;
+
+/// The `verbosity` field of the request, field 5. It is newer than our generated protocol code,
+/// so it is read from the unknown fields.
+pub(crate) fn verbosity(request: &WorkRequest) -> u64 {
+    request
+        .get_unknown_fields()
+        .get(5)
+        .and_then(|values| values.varint.last().copied())
+        .unwrap_or(0)
+}
+
+pub(crate) struct Sink {
+    file: File,
+}
+
+impl Sink {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Sink { file })
+    }
+
+    pub(crate) fn write(
+        &self,
+        request_id: i32,
+        crate_name: Option<&str>,
+        exit_code: i32,
+        usage: &Usage,
+    ) -> io::Result<()> {
+        let entry = Value::object()
+            .with("time_ms", record::now_ms())
+            .with("pid", std::process::id())
+            .with("request_id", request_id)
+            .with("crate", crate_name)
+            .with("exit_code", exit_code)
+            .with("wall_ms", usage.wall.as_millis() as u64)
+            .with("user_ms", usage.user.as_millis() as u64)
+            .with("system_ms", usage.system.as_millis() as u64)
+            .with("max_rss_bytes", usage.max_rss)
+            .with("block_in", usage.block_in)
+            .with("block_out", usage.block_out);
+        // A single write keeps lines from concurrent workers from interleaving.
+        (&self.file).write_all(format!("{}\n", entry).as_bytes())
+    }
+}
+
+/// A one line summary of the usage for the response output.
+pub(crate) fn describe(crate_name: Option<&str>, usage: &Usage) -> String {
+    format!(
+        "rustc-worker: {} took {:.2}s (user {:.2}s, system {:.2}s), peak RSS {}, \
+         {} blocks read, {} blocks written\n",
+        crate_name.unwrap_or("rustc"),
+        usage.wall.as_secs_f64(),
+        usage.user.as_secs_f64(),
+        usage.system.as_secs_f64(),
+        cache::format_size(usage.max_rss),
+        usage.block_in,
+        usage.block_out
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_describe() {
+        let usage = Usage {
+            wall: Duration::from_millis(1500),
+            user: Duration::from_millis(1200),
+            system: Duration::from_millis(250),
+            max_rss: 300 * 1024 * 1024,
+            block_in: 8,
+            block_out: 2048,
+        };
+        assert_eq!(
+            describe(Some("foo"), &usage),
+            "rustc-worker: foo took 1.50s (user 1.20s, system 0.25s), peak RSS 300.0 MiB, \
+             8 blocks read, 2048 blocks written\n"
+        );
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let mut request = WorkRequest::default();
+        assert_eq!(verbosity(&request), 0);
+        // Field 4 is `cancel`.
+        request.mut_unknown_fields().add_varint(4, 1);
+        assert_eq!(verbosity(&request), 0);
+        request.mut_unknown_fields().add_varint(5, 10);
+        assert_eq!(verbosity(&request), VERBOSE);
+    }
+}
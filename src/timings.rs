@@ -0,0 +1,421 @@
+//! A log of when each compilation ran, kept in `timings.jsonl` in each cache directory by every
+//! worker using the cache, and an HTML report of the last build from it, like
+//! `cargo build --timings`.
+//!
+//! Every compilation adds a line, with the times rustc started and finished in milliseconds
+//! since the epoch:
+//!
+//! ```text
+//! {"start_ms":...,"end_ms":...,"crate":"foo","crate_types":["rlib"],"codegen":true,"pid":...,
+//!  "exit_code":0}
+//! ```
+//!
+//! The log doesn't know where one build ends and the next starts, so the report covers the
+//! compilations since rustc last sat idle for more than a minute.
+
+use crate::cache;
+use crate::history;
+use crate::json;
+use crate::json::Value;
+use crate::manifest::Manifest;
+use crate::rustc_args::RustcArgs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const FILE_NAME: &str = "timings.jsonl";
+
+/// Once the log is this big, the older half of it is dropped.
+const MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How long no compilation may run before the next one is taken to start another build.
+const BUILD_GAP_MS: u64 = 60 * 1000;
+
+/// The width of the charts in pixels, not counting labels.
+const CHART_WIDTH: f64 = 1000.0;
+
+/// What the log keeps about a compilation.
+pub(crate) struct Request<'a> {
+    pub(crate) crate_name: &'a str,
+    pub(crate) args: &'a RustcArgs<'a>,
+    /// When rustc started.
+    pub(crate) start: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) exit_code: i32,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Whether rustc generates code, rather than only metadata or dependency information as it
+/// does for pipelined builds.
+fn codegen(args: &RustcArgs) -> bool {
+    let emit = args.emit();
+    emit.is_empty()
+        || emit
+            .iter()
+            .any(|(kind, _)| *kind != "metadata" && *kind != "dep-info")
+}
+
+/// Records a compilation.
+pub(crate) fn record(cache: &Path, request: &Request) -> io::Result<()> {
+    let path = cache.join(FILE_NAME);
+    let start = millis(request.start);
+    let entry = Value::object()
+        .with("start_ms", start)
+        .with("end_ms", start + request.duration.as_millis() as u64)
+        .with("crate", request.crate_name)
+        .with("crate_types", request.args.values("--crate-type"))
+        .with("codegen", codegen(request.args))
+        .with("pid", std::process::id())
+        .with("exit_code", request.exit_code);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    // A single write keeps lines from concurrent workers from interleaving.
+    file.write_all(format!("{}\n", entry).as_bytes())?;
+    if file.metadata()?.len() > MAX_SIZE {
+        history::trim(&path)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Unit {
+    start_ms: u64,
+    end_ms: u64,
+    crate_name: String,
+    crate_types: Vec<String>,
+    codegen: bool,
+    pid: u64,
+    exit_code: i64,
+    compilation_mode: String,
+}
+
+impl Unit {
+    fn from_json(value: &Value, compilation_mode: &str) -> Option<Self> {
+        let number = |key| value.get(key).and_then(Value::as_i64);
+        let start_ms = number("start_ms")? as u64;
+        Some(Unit {
+            start_ms,
+            end_ms: (number("end_ms")? as u64).max(start_ms),
+            crate_name: value.get("crate")?.as_str()?.to_string(),
+            crate_types: value
+                .get("crate_types")
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|crate_type| crate_type.as_str().map(String::from))
+                .collect(),
+            codegen: value.get("codegen") != Some(&Value::Bool(false)),
+            pid: number("pid").unwrap_or(0) as u64,
+            exit_code: number("exit_code").unwrap_or(0),
+            compilation_mode: compilation_mode.to_string(),
+        })
+    }
+}
+
+fn load(cache: &Path) -> io::Result<Vec<Unit>> {
+    let contents = match std::fs::read_to_string(cache.join(FILE_NAME)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let compilation_mode = Manifest::load(cache)
+        .map(|manifest| manifest.compilation_mode)
+        .unwrap_or_default();
+    Ok(contents
+        .lines()
+        // Skip lines cut short by a crash rather than failing on them.
+        .filter_map(|line| json::parse(line).ok())
+        .filter_map(|entry| Unit::from_json(&entry, &compilation_mode))
+        .collect())
+}
+
+/// The compilations of the last build, sorted by when they started.
+fn last_build(mut units: Vec<Unit>) -> Vec<Unit> {
+    units.sort_by_key(|unit| (unit.start_ms, unit.end_ms));
+    let mut start = 0;
+    let mut end_ms = 0;
+    for (index, unit) in units.iter().enumerate() {
+        if index > 0 && unit.start_ms > end_ms + BUILD_GAP_MS {
+            start = index;
+        }
+        end_ms = end_ms.max(unit.end_ms);
+    }
+    units.split_off(start)
+}
+
+/// How many compilations ran at once, as the times the number changed and the number from then
+/// on.
+fn concurrency(units: &[Unit]) -> Vec<(u64, usize)> {
+    let mut events: Vec<(u64, bool)> = units
+        .iter()
+        .flat_map(|unit| vec![(unit.start_ms, true), (unit.end_ms, false)])
+        .collect();
+    // Ends before starts at the same time, so back to back compilations don't overlap. The
+    // count can dip below zero in between, when a compilation starts and ends at the same time.
+    events.sort();
+    let mut steps: Vec<(u64, i64)> = Vec::new();
+    let mut running = 0;
+    for (time, start) in events {
+        if start {
+            running += 1;
+        } else {
+            running -= 1;
+        }
+        match steps.last_mut() {
+            Some(last) if last.0 == time => last.1 = running,
+            _ => steps.push((time, running)),
+        }
+    }
+    steps
+        .into_iter()
+        .map(|(time, running)| (time, running as usize))
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{:.2}s", ms as f64 / 1000.0)
+}
+
+/// The timeline of the compilations, one row each.
+fn gantt(units: &[Unit], start_ms: u64, scale: f64) -> String {
+    const ROW: f64 = 20.0;
+    const LABELS: f64 = 300.0;
+    let mut svg = format!(
+        "<svg width=\"{}\" height=\"{}\" font-size=\"12\">\n",
+        CHART_WIDTH + LABELS,
+        ROW * units.len() as f64
+    );
+    for (row, unit) in units.iter().enumerate() {
+        let x = (unit.start_ms - start_ms) as f64 * scale;
+        let width = ((unit.end_ms - unit.start_ms) as f64 * scale).max(1.0);
+        let y = row as f64 * ROW;
+        let class = if unit.exit_code != 0 {
+            "failed"
+        } else if unit.codegen {
+            "codegen"
+        } else {
+            "metadata"
+        };
+        svg.push_str(&format!(
+            "<rect class=\"{}\" x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\">\
+             <title>{} ({}) {}</title></rect>\n",
+            class,
+            x,
+            y + 2.0,
+            width,
+            ROW - 4.0,
+            escape(&unit.crate_name),
+            escape(&unit.compilation_mode),
+            seconds(unit.end_ms - unit.start_ms),
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\">{} {}</text>\n",
+            x + width + 4.0,
+            y + ROW - 6.0,
+            escape(&unit.crate_name),
+            seconds(unit.end_ms - unit.start_ms),
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// The number of compilations running over time.
+fn concurrency_graph(steps: &[(u64, usize)], start_ms: u64, scale: f64) -> String {
+    const HEIGHT: f64 = 150.0;
+    let max = steps
+        .iter()
+        .map(|(_, running)| *running)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let y = |running: usize| HEIGHT - running as f64 * HEIGHT / max as f64;
+    let mut points = vec![format!("0,{:.1}", HEIGHT)];
+    let mut running = 0;
+    for (time, now) in steps {
+        let x = (time - start_ms) as f64 * scale;
+        points.push(format!("{:.1},{:.1}", x, y(running)));
+        points.push(format!("{:.1},{:.1}", x, y(*now)));
+        running = *now;
+    }
+    format!(
+        "<svg width=\"{}\" height=\"{}\" font-size=\"12\">\n\
+         <polyline class=\"concurrency\" points=\"{}\"/>\n\
+         <text x=\"{}\" y=\"12\">{} at most</text>\n\
+         </svg>\n",
+        CHART_WIDTH + 100.0,
+        HEIGHT,
+        points.join(" "),
+        CHART_WIDTH + 4.0,
+        max
+    )
+}
+
+const STYLE: &str = "body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+td, th { padding: 2px 8px; text-align: left; }
+tr:nth-child(even) { background: #f0f0f0; }
+rect.codegen { fill: #4e79a7; }
+rect.metadata { fill: #a0cbe8; }
+rect.failed { fill: #e15759; }
+polyline.concurrency { fill: none; stroke: #4e79a7; stroke-width: 2; }";
+
+fn report(units: &[Unit]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>rustc-worker timings</title>\n<style>\n{}\n</style>\n</head>\n<body>\n\
+         <h1>rustc-worker timings</h1>\n",
+        STYLE
+    );
+    let start_ms = units.iter().map(|unit| unit.start_ms).min().unwrap_or(0);
+    let end_ms = units.iter().map(|unit| unit.end_ms).max().unwrap_or(0);
+    let mut workers: Vec<u64> = units.iter().map(|unit| unit.pid).collect();
+    workers.sort_unstable();
+    workers.dedup();
+    let steps = concurrency(units);
+    html.push_str(&format!(
+        "<table>\n<tr><td>Compilations</td><td>{}</td></tr>\n\
+         <tr><td>Failed</td><td>{}</td></tr>\n\
+         <tr><td>Wall time</td><td>{}</td></tr>\n\
+         <tr><td>Workers</td><td>{}</td></tr>\n\
+         <tr><td>Most concurrent compilations</td><td>{}</td></tr>\n</table>\n",
+        units.len(),
+        units.iter().filter(|unit| unit.exit_code != 0).count(),
+        seconds(end_ms - start_ms),
+        workers.len(),
+        steps.iter().map(|(_, running)| *running).max().unwrap_or(0),
+    ));
+    let scale = CHART_WIDTH / (end_ms - start_ms).max(1) as f64;
+    html.push_str("<h2>Timeline</h2>\n<p>Dark: with code generation. Light: metadata only. ");
+    html.push_str("Red: failed.</p>\n");
+    html.push_str(&gantt(units, start_ms, scale));
+    html.push_str("<h2>Concurrency</h2>\n");
+    html.push_str(&concurrency_graph(&steps, start_ms, scale));
+
+    let mut slowest: Vec<&Unit> = units.iter().collect();
+    slowest.sort_by_key(|unit| std::cmp::Reverse(unit.end_ms - unit.start_ms));
+    html.push_str(
+        "<h2>Compilations</h2>\n<table>\n<tr><th>Crate</th><th>Mode</th><th>Type</th>\
+         <th>Codegen</th><th>Start</th><th>Duration</th><th>Worker</th></tr>\n",
+    );
+    for unit in slowest {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td></tr>\n",
+            escape(&unit.crate_name),
+            escape(&unit.compilation_mode),
+            escape(&unit.crate_types.join(", ")),
+            if unit.codegen { "yes" } else { "no" },
+            seconds(unit.start_ms - start_ms),
+            seconds(unit.end_ms - unit.start_ms),
+            unit.pid,
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Writes an HTML report of the last build with the given caches, or every cache when none are
+/// given, taking the compilations of all of them together.
+pub fn report_html<W: io::Write>(caches: &[PathBuf], out: &mut W) -> io::Result<()> {
+    let caches = if caches.is_empty() {
+        cache::discover()?
+    } else {
+        caches.to_vec()
+    };
+    let mut units = Vec::new();
+    for cache in &caches {
+        units.extend(load(cache)?);
+    }
+    out.write_all(report(&last_build(units)).as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unit(crate_name: &str, start_ms: u64, end_ms: u64) -> Unit {
+        Unit {
+            start_ms,
+            end_ms,
+            crate_name: crate_name.to_string(),
+            crate_types: vec!["rlib".to_string()],
+            codegen: true,
+            pid: 1,
+            exit_code: 0,
+            compilation_mode: "fastbuild".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_codegen() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert!(codegen(&RustcArgs::new(&args(&["--crate-name=foo"]))));
+        assert!(codegen(&RustcArgs::new(&args(&["--emit=dep-info,link"]))));
+        assert!(!codegen(&RustcArgs::new(&args(&[
+            "--emit=dep-info,metadata"
+        ]))));
+    }
+
+    #[test]
+    fn test_last_build() {
+        let units = vec![
+            unit("b", 100_000, 101_000),
+            unit("old", 0, 1000),
+            unit("a", 100_000, 100_500),
+            unit("c", 100_500, 102_000),
+        ];
+        let names: Vec<String> = last_build(units)
+            .into_iter()
+            .map(|unit| unit.crate_name)
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let units = [unit("a", 0, 10), unit("b", 5, 20), unit("c", 10, 15)];
+        assert_eq!(
+            concurrency(&units),
+            [(0, 1), (5, 2), (10, 2), (15, 1), (20, 0)]
+        );
+        // Compilations that take no time at all, on their own and next to others.
+        let units = [unit("a", 0, 10), unit("b", 10, 10), unit("c", 20, 20)];
+        assert_eq!(concurrency(&units), [(0, 1), (10, 0), (20, 0)]);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut failed = unit("<bad>", 500, 1000);
+        failed.exit_code = 1;
+        let html = report(&[unit("foo", 0, 1000), failed]);
+        assert!(
+            html.contains("<tr><td>Wall time</td><td>1.00s</td></tr>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<tr><td>Most concurrent compilations</td><td>2</td></tr>"));
+        assert!(
+            html.contains("<rect class=\"failed\" x=\"500.0\""),
+            "{}",
+            html
+        );
+        assert!(html.contains("&lt;bad&gt;"));
+    }
+}
@@ -0,0 +1,120 @@
+//! A trace of the requests a worker handles, in the Trace Event format that `chrome://tracing`
+//! and Perfetto load.
+//!
+//! The file is a JSON array that is never closed, which both viewers accept, so that several
+//! worker processes can append events to the same trace. Each worker process gets a track of its
+//! own, and each request is a slice named after the crate with a `wait` slice for the time
+//! before rustc started (mostly waiting for the crate lock) and a `rustc` slice for rustc itself.
+
+use crate::json::Value;
+use crate::lock;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// What the trace shows about a request.
+pub(crate) struct Request<'a> {
+    pub(crate) crate_name: Option<&'a str>,
+    pub(crate) request_id: i32,
+    /// When the worker read the request.
+    pub(crate) received: SystemTime,
+    /// From reading the request until rustc started.
+    pub(crate) wait: Duration,
+    pub(crate) rustc: Duration,
+    /// Whether rustc used the shared incremental cache.
+    pub(crate) incremental: bool,
+    pub(crate) exit_code: i32,
+}
+
+pub(crate) struct Trace {
+    file: File,
+    pid: u32,
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+impl Trace {
+    /// Opens the trace for appending, starting the array if this is the first worker to use it,
+    /// and names the worker's track.
+    pub(crate) fn open(path: &Path, compilation_mode: &str) -> io::Result<Self> {
+        {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            // Keeps two workers from both starting the array.
+            lock::lock_file(&file)?;
+            if file.metadata()?.len() == 0 {
+                (&file).write_all(b"[\n")?;
+            }
+        }
+        let trace = Trace {
+            file: OpenOptions::new().append(true).open(path)?,
+            pid: std::process::id(),
+        };
+        let name = format!("rustc-worker {} ({})", trace.pid, compilation_mode);
+        trace.write(&[
+            trace.metadata("process_name", name),
+            trace.metadata("thread_name", "requests".to_string()),
+        ])?;
+        Ok(trace)
+    }
+
+    fn metadata(&self, kind: &str, name: String) -> Value {
+        Value::object()
+            .with("name", kind)
+            .with("ph", "M")
+            .with("pid", self.pid)
+            .with("tid", 0)
+            .with("args", Value::object().with("name", name))
+    }
+
+    fn slice(&self, name: &str, start: u64, duration: Duration, args: Value) -> Value {
+        Value::object()
+            .with("name", name)
+            .with("cat", "rustc-worker")
+            .with("ph", "X")
+            .with("ts", start)
+            .with("dur", duration.as_micros() as u64)
+            .with("pid", self.pid)
+            .with("tid", 0)
+            .with("args", args)
+    }
+
+    pub(crate) fn request(&self, request: &Request) -> io::Result<()> {
+        let start = micros(request.received);
+        let args = Value::object()
+            .with("crate", request.crate_name)
+            .with("request_id", request.request_id)
+            .with("wait_ms", request.wait.as_secs_f64() * 1000.0)
+            .with("rustc_ms", request.rustc.as_secs_f64() * 1000.0)
+            .with("incremental", request.incremental)
+            .with("exit_code", request.exit_code);
+        let rustc_start = start + request.wait.as_micros() as u64;
+        self.write(&[
+            self.slice(
+                request.crate_name.unwrap_or("rustc"),
+                start,
+                request.wait + request.rustc,
+                args,
+            ),
+            self.slice("wait", start, request.wait, Value::object()),
+            self.slice("rustc", rustc_start, request.rustc, Value::object()),
+        ])
+    }
+
+    fn write(&self, events: &[Value]) -> io::Result<()> {
+        let mut text = String::new();
+        for event in events {
+            text.push_str(&format!("{},\n", event));
+        }
+        // A single write keeps events from concurrent workers from interleaving.
+        (&self.file).write_all(text.as_bytes())
+    }
+}
@@ -0,0 +1,414 @@
+//! Reports `--extern` dependencies that a crate declares but never uses.
+//!
+//! rustc is asked to force-warn `unused-crate-dependencies`, which cannot be turned into an
+//! error by `#![deny(warnings)]`. Versions that can't force-warn lints are asked to warn
+//! instead, and the worker compiles the crate again without the lint when that fails the build.
+//! The warnings are collected into a JSON report per target and removed from the output again,
+//! unless the request enabled the lint itself.
+
+use crate::diagnostics;
+use crate::json;
+use crate::json::Value;
+use crate::rustc_args::RustcArgs;
+use std::io;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+const LINT: &str = "unused-crate-dependencies";
+
+/// How rustc is asked for the lint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Lint {
+    /// With these flags, which nothing in the crate or the request can override.
+    ForceWarn(&'static [&'static str]),
+    /// With `-W`, which `#![deny(warnings)]` turns into an error.
+    Warn,
+}
+
+impl Lint {
+    /// How to ask for the lint, given the output of `rustc -vV`. `--force-warn` is stable since
+    /// rustc 1.56 and needs `-Z unstable-options` on the nightlies before that, back to 1.53.
+    pub(crate) fn new(version: &str) -> Self {
+        match force_warn_flags(version) {
+            Some(flags) => Lint::ForceWarn(flags),
+            None => Lint::Warn,
+        }
+    }
+
+    /// The flags to add to the request, or `None` when rustc can't report unused crates for it
+    /// without changing how it compiles.
+    pub(crate) fn flags(self, args: &RustcArgs) -> Option<&'static [&'static str]> {
+        match self {
+            Lint::ForceWarn(flags) => Some(flags),
+            // The request's own level applies.
+            Lint::Warn if requested(args) => Some(&[]),
+            // Capped lints are never reported, and denied warnings would fail the build.
+            Lint::Warn
+                if args.values("--cap-lints").contains(&"allow")
+                    || ["-D", "--deny", "-F", "--forbid"]
+                        .iter()
+                        .flat_map(|flag| args.values(flag))
+                        .any(|lint| lint == "warnings") =>
+            {
+                None
+            }
+            Lint::Warn => Some(&["-W", LINT]),
+        }
+    }
+}
+
+fn force_warn_flags(version: &str) -> Option<&'static [&'static str]> {
+    // Like "rustc 1.47.0-nightly (663d2f5cd 2020-08-22)".
+    let release = version.split_whitespace().nth(1)?;
+    let mut numbers = release.split(|c| c == '.' || c == '-');
+    let major: u32 = numbers.next()?.parse().ok()?;
+    let minor: u32 = numbers.next()?.parse().ok()?;
+    let nightly = release.contains("-nightly") || release.contains("-dev");
+    if major > 1 || minor >= 56 {
+        Some(&["--force-warn", LINT])
+    } else if minor >= 53 && nightly {
+        Some(&["-Z", "unstable-options", "--force-warn", LINT])
+    } else {
+        None
+    }
+}
+
+/// Whether the request asked for the lint, in which case its warnings are left in the output.
+pub(crate) fn requested(args: &RustcArgs) -> bool {
+    [
+        "-W",
+        "--warn",
+        "-D",
+        "--deny",
+        "-F",
+        "--forbid",
+        "--force-warn",
+    ]
+    .iter()
+    .flat_map(|flag| args.values(flag))
+    .any(|lint| lint.replace('_', "-") == LINT)
+}
+
+/// The crates declared with `--extern name=path`, ignoring modifiers such as `priv:`.
+pub(crate) fn externs<'a>(args: &RustcArgs<'a>) -> Vec<(&'a str, Option<&'a str>)> {
+    args.values("--extern")
+        .into_iter()
+        .map(|value| {
+            let mut parts = value.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let name = name.rsplit(':').next().unwrap();
+            (name, parts.next())
+        })
+        .collect()
+}
+
+/// Returns the unused crate named by an `unused-crate-dependencies` diagnostic line at `level`.
+fn unused_crate<'a>(level: &str, line: &'a str) -> Option<&'a str> {
+    let message = line.strip_prefix(level)?.strip_prefix(": ")?;
+    // rustc has worded this as both "external crate `x` unused in `y`" and
+    // "extern crate `x` is unused in crate `y`".
+    let rest = message
+        .strip_prefix("external crate `")
+        .or_else(|| message.strip_prefix("extern crate `"))?;
+    let end = rest.find('`')?;
+    if rest[end..].contains("unused in") {
+        Some(&rest[..end])
+    } else {
+        None
+    }
+}
+
+/// Returns the crates reported as unused in the rustc output.
+pub(crate) fn parse(stderr: &str) -> Vec<String> {
+    diagnostics::strip_ansi(stderr)
+        .lines()
+        .filter_map(|line| unused_crate("warning", line))
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the crates the rustc output reports as unused with errors, which means the crate
+/// turned the warnings into errors.
+pub(crate) fn denied(stderr: &str) -> Vec<String> {
+    diagnostics::strip_ansi(stderr)
+        .lines()
+        .filter_map(|line| unused_crate("error", line))
+        .map(String::from)
+        .collect()
+}
+
+/// Removes the `unused-crate-dependencies` warnings, and fixes up the warning count rustc
+/// prints at the end.
+pub(crate) fn strip(stderr: &str) -> String {
+    let mut out = String::new();
+    let mut stripped = 0;
+    let mut skipping = false;
+    for line in crate::split_lines(stderr) {
+        if skipping {
+            // A diagnostic ends with an empty line.
+            skipping = !line.trim_end().is_empty();
+            continue;
+        }
+        if unused_crate("warning", diagnostics::strip_ansi(line).trim_end()).is_some() {
+            stripped += 1;
+            skipping = true;
+            continue;
+        }
+        out.push_str(line);
+    }
+    if stripped == 0 {
+        return out;
+    }
+    let mut fixed = String::new();
+    let mut lines = crate::split_lines(&out).into_iter();
+    while let Some(line) = lines.next() {
+        match warning_count(&diagnostics::strip_ansi(line)) {
+            Some(count) if count <= stripped => {
+                // Drop the summary and the empty line after it.
+                lines.next();
+            }
+            Some(count) => {
+                let count = count - stripped;
+                fixed.push_str(&format!(
+                    "warning: {} warning{} emitted\n",
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ));
+            }
+            None => fixed.push_str(line),
+        }
+    }
+    fixed
+}
+
+/// Removes the `unused-crate-dependencies` warnings from JSON diagnostics.
+pub(crate) fn strip_diagnostics(diagnostics: &mut Vec<Value>) {
+    diagnostics.retain(|diagnostic| {
+        let message = diagnostic.get("message").and_then(Value::as_str);
+        let level = diagnostic.get("level").and_then(Value::as_str);
+        match (level, message) {
+            (Some(level), Some(message)) => {
+                unused_crate("warning", &format!("{}: {}", level, message)).is_none()
+            }
+            _ => true,
+        }
+    });
+}
+
+fn warning_count(line: &str) -> Option<usize> {
+    line.trim_end()
+        .strip_prefix("warning: ")?
+        .strip_suffix(" emitted")?
+        .split(' ')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Where a target's package lives, inferred from an output directory such as
+/// `bazel-out/k8-fastbuild/bin/foo/bar` or `bazel-out/k8-fastbuild/bin/external/repo/foo`.
+pub(crate) fn package_dir(out_dir: &Path) -> Option<PathBuf> {
+    let mut components = out_dir.components().skip_while(|c| *c == Component::CurDir);
+    if components.next()? != Component::Normal("bazel-out".as_ref()) {
+        return None;
+    }
+    components.next()?;
+    if components.next()? != Component::Normal("bin".as_ref()) {
+        return None;
+    }
+    Some(components.collect())
+}
+
+/// The Bazel label of the target, assuming the crate is named after it.
+pub(crate) fn label(package: &Path, crate_name: &str) -> String {
+    let mut components = package.iter();
+    if components.next() == Some("external".as_ref()) {
+        if let Some(repo) = components.next() {
+            let package: PathBuf = components.collect();
+            return format!(
+                "@{}//{}:{}",
+                repo.to_string_lossy(),
+                package.display(),
+                crate_name
+            );
+        }
+    }
+    format!("//{}:{}", package.display(), crate_name)
+}
+
+/// Writes the report for one crate to `<report_dir>/<package>/<file stem>.json`.
+pub(crate) fn write_report(
+    report_dir: &Path,
+    args: &RustcArgs,
+    unused: &[String],
+) -> io::Result<PathBuf> {
+    let crate_name = args.crate_name().unwrap_or("unknown");
+    let package = args.out_dir().as_deref().and_then(package_dir);
+    let externs = externs(args);
+    let to_json = |name: &str, path: Option<&str>| {
+        json::Value::object().with("name", name).with("path", path)
+    };
+    let report = json::Value::object()
+        .with("label", package.as_deref().map(|p| label(p, crate_name)))
+        .with("crate_name", crate_name)
+        .with(
+            "declared",
+            externs
+                .iter()
+                .map(|&(name, path)| to_json(name, path))
+                .collect::<Vec<_>>(),
+        )
+        .with(
+            "unused",
+            externs
+                .iter()
+                .filter(|(name, _)| unused.iter().any(|u| u == name))
+                .map(|&(name, path)| to_json(name, path))
+                .collect::<Vec<_>>(),
+        );
+
+    let mut path = report_dir.join(package.unwrap_or_default());
+    std::fs::create_dir_all(&path)?;
+    let stem = args.file_stem().unwrap_or_else(|| "unknown".to_string());
+    path.push(format!("{}.json", stem));
+    std::fs::write(&path, report.pretty() + "\n")?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STDERR: &str = "warning: unused variable: `x`\n\
+                          \x20--> src/lib.rs:1:5\n\
+                          \n\
+                          warning: extern crate `dep` is unused in crate `user`\n\
+                          \x20 |\n\
+                          \x20 = help: remove the dependency or add `use dep as _;` to the crate root\n\
+                          \n\
+                          warning: 2 warnings emitted\n\
+                          \n";
+
+    #[test]
+    fn test_requested() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        for flags in &[
+            &["-W", "unused-crate-dependencies"][..],
+            &["-Wunused-crate-dependencies"],
+            &["--warn=unused_crate_dependencies"],
+            &["-Dunused-crate-dependencies"],
+        ] {
+            assert!(requested(&RustcArgs::new(&args(flags))), "{:?}", flags);
+        }
+        assert!(!requested(&RustcArgs::new(&args(&["-Wunused"]))));
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(
+            Lint::new("rustc 1.56.0 (09c42c458 2021-10-18)\n"),
+            Lint::ForceWarn(&["--force-warn", LINT])
+        );
+        assert_eq!(
+            Lint::new("rustc 1.54.0-nightly (657bc0188 2021-05-31)\n"),
+            Lint::ForceWarn(&["-Z", "unstable-options", "--force-warn", LINT])
+        );
+        assert_eq!(
+            Lint::new("rustc 1.54.0 (a178d0322 2021-07-26)\n"),
+            Lint::Warn
+        );
+        assert_eq!(
+            Lint::new("rustc 1.47.0-nightly (663d2f5cd 2020-08-22)\n"),
+            Lint::Warn
+        );
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = |lint: Lint, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            lint.flags(&RustcArgs::new(&args))
+        };
+        let force_warn = Lint::ForceWarn(&["--force-warn", LINT]);
+        assert_eq!(
+            flags(force_warn, &["-Dwarnings"]),
+            Some(&["--force-warn", LINT][..])
+        );
+        assert_eq!(
+            flags(Lint::Warn, &["--crate-name=foo"]),
+            Some(&["-W", LINT][..])
+        );
+        assert_eq!(
+            flags(Lint::Warn, &["-D", "unused_crate_dependencies"]),
+            Some(&[][..])
+        );
+        assert_eq!(flags(Lint::Warn, &["-D", "warnings"]), None);
+        assert_eq!(flags(Lint::Warn, &["--forbid=warnings"]), None);
+        assert_eq!(flags(Lint::Warn, &["--cap-lints", "allow"]), None);
+        assert_eq!(
+            flags(Lint::Warn, &["--cap-lints", "warn"]),
+            Some(&["-W", LINT][..])
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(STDERR), vec!["dep".to_string()]);
+        assert_eq!(
+            parse("warning: external crate `a` unused in `b`: remove the dependency\n"),
+            vec!["a".to_string()]
+        );
+        assert!(denied(STDERR).is_empty());
+        assert_eq!(
+            denied("error: extern crate `dep` is unused in crate `user`\n"),
+            vec!["dep".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_strip() {
+        assert_eq!(
+            strip(STDERR),
+            "warning: unused variable: `x`\n\
+             \x20--> src/lib.rs:1:5\n\
+             \n\
+             warning: 1 warning emitted\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn test_label() {
+        let package = package_dir(Path::new("bazel-out/k8-fastbuild/bin/foo/bar")).unwrap();
+        assert_eq!(label(&package, "baz"), "//foo/bar:baz");
+        let package = package_dir(Path::new("bazel-out/k8-opt/bin/external/repo/foo")).unwrap();
+        assert_eq!(label(&package, "baz"), "@repo//foo:baz");
+        assert_eq!(package_dir(Path::new("/tmp/out")), None);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let dir =
+            std::env::temp_dir().join(format!("rustc-worker-unused-test-{}", std::process::id()));
+        let library = [
+            "--crate-name=foo",
+            "--out-dir=bazel-out/k8-fastbuild/bin/foo",
+        ];
+        let test = [
+            "--crate-name=foo",
+            "--out-dir=bazel-out/k8-fastbuild/bin/foo",
+            "--test",
+        ];
+        let mut written = Vec::new();
+        for args in [&library[..], &test[..]].iter() {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            written.push(write_report(&dir, &RustcArgs::new(&args), &[]).unwrap());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            written,
+            [dir.join("foo/foo.json"), dir.join("foo/foo.test.json")]
+        );
+    }
+}
@@ -0,0 +1,492 @@
+//! Shadow verification of incremental builds: a sample of requests is compiled a second time
+//! into a scratch directory, without reusing anything, and the outputs of both compilations are
+//! compared.
+//!
+//! The clean compilation still passes rustc an incremental directory, an empty one. With
+//! incremental compilation off entirely, rustc splits the crate into different codegen units and
+//! writes different metadata, so its outputs would never match and every request would be
+//! reported.
+//!
+//! Some differences are expected and ignored: paths to the scratch directory in the outputs
+//! (as in `.d` files), and the random suffix rustc gives the object files inside an rlib in
+//! every incremental session.
+
+use crate::cache;
+use crate::child;
+use crate::limits::Limits;
+use crate::lock::PrivateDir;
+use crate::rustc_args::RustcArgs;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// The directory bundles are saved in unless `--verify_bundles` says otherwise.
+const BUNDLES: &str = "rustc-worker-verify";
+
+/// What to do when an incremental compilation does not match a clean one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verify {
+    /// Don't compare.
+    Off,
+    /// Add a warning to the response output.
+    Warn,
+    /// Fail the request.
+    Error,
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Verify::Off
+    }
+}
+
+impl std::str::FromStr for Verify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Verify::Off),
+            "warn" => Ok(Verify::Warn),
+            "error" => Ok(Verify::Error),
+            _ => Err(format!("unknown verify mode {:?}", s)),
+        }
+    }
+}
+
+/// The outputs of the incremental compilation that did not match, by their path relative to
+/// the scratch directory.
+struct Mismatch {
+    path: PathBuf,
+    incremental: PathBuf,
+    clean: PathBuf,
+    details: Vec<String>,
+}
+
+/// Compiles the request again from an empty incremental directory and compares the outputs with
+/// the ones the incremental compilation left in `cwd`. Returns a report when they differ, after
+/// saving both sets of outputs to a bundle in `bundles`, or the default directory. The clean
+/// compilation runs under the same limits and timeout as the request.
+pub(crate) fn verify(
+    program: &Path,
+    cwd: &Path,
+    args: &RustcArgs,
+    mode: Verify,
+    bundles: Option<&Path>,
+    limits: &Limits,
+    timeout: Option<Duration>,
+) -> io::Result<Option<String>> {
+    let scratch = PrivateDir::create()?;
+    let outputs = scratch.path().join("outputs");
+    let redirected = match args.redirect_outputs(&outputs) {
+        Some(redirected) => redirected,
+        None => return Ok(None),
+    };
+    for (moved, original) in &redirected.outputs {
+        let dir = if cwd.join(original).is_dir() {
+            moved.as_path()
+        } else {
+            moved.parent().unwrap_or(moved)
+        };
+        std::fs::create_dir_all(dir)?;
+    }
+    let incremental = scratch.path().join("incremental");
+    std::fs::create_dir(&incremental)?;
+    let mut incremental_arg = std::ffi::OsString::from("incremental=");
+    incremental_arg.push(&incremental);
+    let mut cmd = Command::new(program);
+    cmd.args(&redirected.args)
+        .arg("--codegen")
+        .arg(incremental_arg)
+        .current_dir(cwd);
+    let cgroup = limits.apply(&mut cmd)?;
+    let finished = child::run(&mut cmd, timeout)?;
+    if finished.timed_out {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "the clean compilation did not finish within {:?}",
+                timeout.unwrap_or_default()
+            ),
+        ));
+    }
+    let output = finished.output;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let limit = limits.explain(output.status, &stderr, cgroup.as_ref());
+        return Ok(Some(format!(
+            "{}: the clean compilation to verify against failed ({}):\n{}{}",
+            level(mode),
+            output.status,
+            stderr,
+            limit.unwrap_or_default()
+        )));
+    }
+
+    let replacements: Vec<(Vec<u8>, Vec<u8>)> = redirected
+        .outputs
+        .iter()
+        .map(|(moved, original)| {
+            (
+                moved.to_string_lossy().into_owned().into_bytes(),
+                original.to_string_lossy().into_owned().into_bytes(),
+            )
+        })
+        .collect();
+    let mut mismatches = Vec::new();
+    for (moved, original) in &redirected.outputs {
+        for file in files(moved)? {
+            let clean = join(moved, &file);
+            let incremental = join(&cwd.join(original), &file);
+            let details = compare(&incremental, &clean, &replacements)?;
+            if !details.is_empty() {
+                mismatches.push(Mismatch {
+                    path: clean.strip_prefix(&outputs).unwrap_or(&clean).to_owned(),
+                    incremental,
+                    clean,
+                    details,
+                });
+            }
+        }
+    }
+    if mismatches.is_empty() {
+        return Ok(None);
+    }
+    let crate_name = args.crate_name().unwrap_or("rustc");
+    let mut report = format!(
+        "{}: the incremental compilation of {} does not match a clean compilation:\n",
+        level(mode),
+        crate_name
+    );
+    for mismatch in &mismatches {
+        for detail in &mismatch.details {
+            report.push_str(&format!("  {}: {}\n", mismatch.path.display(), detail));
+        }
+    }
+    match save_bundle(bundles, crate_name, &redirected.args, &mismatches, &report) {
+        Ok(bundle) => report.push_str(&format!("  saved both to {}\n", bundle.display())),
+        Err(e) => report.push_str(&format!("  could not save them: {}\n", e)),
+    }
+    Ok(Some(report))
+}
+
+fn level(mode: Verify) -> &'static str {
+    match mode {
+        Verify::Error => "error",
+        _ => "warning",
+    }
+}
+
+/// The files under `path`, relative to it. A file on its own is the empty path.
+fn files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(if path.exists() {
+            vec![PathBuf::new()]
+        } else {
+            Vec::new()
+        });
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let name = PathBuf::from(entry?.file_name());
+        files.extend(files_in(path, &name)?);
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn files_in(root: &Path, relative: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(files(&root.join(relative))?
+        .into_iter()
+        .map(|file| join(relative, &file))
+        .collect())
+}
+
+/// Joins a path from `files`, which can be empty, to a directory.
+fn join(dir: &Path, relative: &Path) -> PathBuf {
+    if relative.as_os_str().is_empty() {
+        dir.to_owned()
+    } else {
+        dir.join(relative)
+    }
+}
+
+/// How the incremental output differs from the clean one, if it does.
+fn compare(
+    incremental: &Path,
+    clean: &Path,
+    replacements: &[(Vec<u8>, Vec<u8>)],
+) -> io::Result<Vec<String>> {
+    let incremental = match std::fs::read(incremental) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(vec!["missing from the incremental compilation".to_string()]);
+        }
+        Err(e) => return Err(e),
+    };
+    let clean = replace(std::fs::read(clean)?, replacements);
+    let (incremental, clean) = (digests(&incremental), digests(&clean));
+    let mut details = Vec::new();
+    for (name, digest) in &clean {
+        match incremental.get(name) {
+            Some(other) if other == digest => {}
+            Some(other) => details.push(describe(name, &format!("{:016x}", other), digest)),
+            None => details.push(describe(name, "missing", digest)),
+        }
+    }
+    for (name, digest) in &incremental {
+        if !clean.contains_key(name) {
+            details.push(format!(
+                "{} {:016x} is not in the clean compilation",
+                name, digest
+            ));
+        }
+    }
+    Ok(details)
+}
+
+fn describe(name: &str, incremental: &str, clean: &u64) -> String {
+    let what = if name.is_empty() {
+        "digest".to_string()
+    } else {
+        format!("{} digest", name)
+    };
+    format!(
+        "{} {} in the incremental compilation, {:016x} in the clean one",
+        what, incremental, clean
+    )
+}
+
+fn replace(bytes: Vec<u8>, replacements: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = bytes;
+    for (from, to) in replacements {
+        if from.is_empty() {
+            continue;
+        }
+        let mut replaced = Vec::with_capacity(bytes.len());
+        let mut rest = &bytes[..];
+        while let Some(at) = rest
+            .windows(from.len())
+            .position(|window| window == &from[..])
+        {
+            replaced.extend_from_slice(&rest[..at]);
+            replaced.extend_from_slice(to);
+            rest = &rest[at + from.len()..];
+        }
+        replaced.extend_from_slice(rest);
+        bytes = replaced;
+    }
+    bytes
+}
+
+fn digest(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// The digests of an output, by member for archives and under the empty name for anything else.
+fn digests(bytes: &[u8]) -> BTreeMap<String, u64> {
+    match archive_members(bytes) {
+        Some(members) => members
+            .into_iter()
+            .map(|(name, contents)| (normalize_member(&name), digest(contents)))
+            .collect(),
+        None => std::iter::once((String::new(), digest(bytes))).collect(),
+    }
+}
+
+/// Drops the session suffix from object files in incremental rlibs, so that
+/// `foo.3kd8sl9xemz4n6sd.0uq305p.rcgu.o` becomes `foo.3kd8sl9xemz4n6sd.rcgu.o`.
+fn normalize_member(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix(".rcgu.o") {
+        let parts: Vec<&str> = stem.split('.').collect();
+        if parts.len() >= 3 {
+            return format!("{}.rcgu.o", parts[..parts.len() - 1].join("."));
+        }
+    }
+    name.to_string()
+}
+
+/// The members of an `ar` archive, as rustc writes rlibs in the GNU or BSD variant, leaving out
+/// the symbol table. `None` if `bytes` is not an archive.
+fn archive_members(bytes: &[u8]) -> Option<Vec<(String, &[u8])>> {
+    const MAGIC: &[u8] = b"!<arch>\n";
+    if !bytes.starts_with(MAGIC) {
+        return None;
+    }
+    let mut rest = &bytes[MAGIC.len()..];
+    let mut long_names: &[u8] = &[];
+    let mut members = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 60 {
+            return None;
+        }
+        let (header, after) = rest.split_at(60);
+        let field = |range: std::ops::Range<usize>| {
+            std::str::from_utf8(&header[range]).ok().map(str::trim_end)
+        };
+        let name = field(0..16)?;
+        let size: usize = field(48..58)?.parse().ok()?;
+        if after.len() < size {
+            return None;
+        }
+        let (mut contents, after) = after.split_at(size);
+        rest = after.get(size % 2..).unwrap_or_default();
+        let name = if name == "/" || name == "/SYM64/" || name.starts_with("__.SYMDEF") {
+            continue;
+        } else if name == "//" {
+            long_names = contents;
+            continue;
+        } else if let Some(length) = name.strip_prefix("#1/") {
+            // BSD puts long names at the start of the contents.
+            let length: usize = length.parse().ok()?;
+            let (long_name, member) = contents.split_at(length.min(contents.len()));
+            contents = member;
+            let long_name = String::from_utf8_lossy(long_name);
+            let long_name = long_name.trim_end_matches('\0').to_string();
+            if long_name.starts_with("__.SYMDEF") {
+                continue;
+            }
+            long_name
+        } else if let Some(offset) = name.strip_prefix('/') {
+            let offset: usize = offset.parse().ok()?;
+            let long_name = long_names.get(offset..)?;
+            let end = long_name.iter().position(|&b| b == b'\n')?;
+            String::from_utf8_lossy(&long_name[..end])
+                .trim_end_matches('/')
+                .to_string()
+        } else {
+            name.trim_end_matches('/').to_string()
+        };
+        members.push((name, contents));
+    }
+    Some(members)
+}
+
+/// Copies the mismatched outputs of both compilations, the rustc arguments and the report into
+/// a new directory in `bundles`, or in the default directory, which is created privately like
+/// the caches.
+fn save_bundle(
+    bundles: Option<&Path>,
+    crate_name: &str,
+    args: &[String],
+    mismatches: &[Mismatch],
+    report: &str,
+) -> io::Result<PathBuf> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let bundles = match bundles {
+        Some(bundles) => bundles.to_path_buf(),
+        None => cache::create(BUNDLES)?,
+    };
+    let bundle = bundles.join(format!("{}-{}-{}", crate_name, secs, std::process::id()));
+    std::fs::create_dir_all(&bundle)?;
+    std::fs::write(bundle.join("args"), args.join("\n") + "\n")?;
+    std::fs::write(bundle.join("report.txt"), report)?;
+    for mismatch in mismatches {
+        for (dir, from) in &[
+            ("incremental", &mismatch.incremental),
+            ("clean", &mismatch.clean),
+        ] {
+            let to = bundle.join(dir).join(&mismatch.path);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if from.exists() {
+                std::fs::copy(from, &to)?;
+            }
+        }
+    }
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut bytes = b"!<arch>\n".to_vec();
+        let mut long_names = Vec::new();
+        let mut headers = Vec::new();
+        for (name, contents) in members {
+            let name = if name.len() > 15 {
+                let name_ref = format!("/{}", long_names.len());
+                long_names.extend_from_slice(format!("{}/\n", name).as_bytes());
+                name_ref
+            } else {
+                format!("{}/", name)
+            };
+            headers.push((name, *contents));
+        }
+        let mut push = |name: &str, contents: &[u8]| {
+            bytes.extend_from_slice(
+                format!(
+                    "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                    name,
+                    0,
+                    0,
+                    0,
+                    644,
+                    contents.len()
+                )
+                .as_bytes(),
+            );
+            bytes.extend_from_slice(contents);
+            if contents.len() % 2 == 1 {
+                bytes.push(b'\n');
+            }
+        };
+        push("/", b"symbols");
+        push("//", &long_names);
+        for (name, contents) in headers {
+            push(&name, contents);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_archive_members() {
+        let bytes = archive(&[
+            ("lib.rmeta", b"metadata"),
+            ("foo.3kd8sl9xemz4n6sd.0uq305p.rcgu.o", b"object"),
+        ]);
+        assert_eq!(
+            archive_members(&bytes).unwrap(),
+            vec![
+                ("lib.rmeta".to_string(), &b"metadata"[..]),
+                (
+                    "foo.3kd8sl9xemz4n6sd.0uq305p.rcgu.o".to_string(),
+                    &b"object"[..]
+                ),
+            ]
+        );
+        assert!(archive_members(b"not an archive").is_none());
+    }
+
+    #[test]
+    fn test_digests() {
+        let incremental = archive(&[("foo.3kd8sl9xemz4n6sd.0uq305p.rcgu.o", b"object")]);
+        let clean = archive(&[("foo.3kd8sl9xemz4n6sd.17hob94.rcgu.o", b"object")]);
+        assert_eq!(digests(&incremental), digests(&clean));
+        let different = archive(&[("foo.3kd8sl9xemz4n6sd.17hob94.rcgu.o", b"objecT")]);
+        assert_ne!(digests(&incremental), digests(&different));
+    }
+
+    #[test]
+    fn test_replace() {
+        let replacements = vec![(b"/scratch/0".to_vec(), b"bazel-out/bin".to_vec())];
+        assert_eq!(
+            replace(b"/scratch/0/foo.d: src/lib.rs".to_vec(), &replacements),
+            b"bazel-out/bin/foo.d: src/lib.rs"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! A stand-in for rustc, compiled by the tests.
+//!
+//! `-vV` prints a fake version. Otherwise it reads its behavior from `<argv[0]>.conf`, one
+//! `key=value` per line:
+//!
+//! - `record=<dir>`: write the arguments to `<dir>/args` and the environment to `<dir>/env`,
+//!   one per line.
+//! - `stderr=<text>`: print the text to stderr, with `\n` for newlines.
+//! - `stderr_hex=<hex>`: print raw bytes to stderr.
+//! - `sleep_ms=<n>`: sleep before exiting.
+//! - `alloc_mb=<n>`: allocate and touch that much memory.
+//! - `write=<path>`: create the file, like an output of the compilation.
+//! - `output=<name>`: write a file naming itself to the `--out-dir`, like a `.d` file.
+//! - `output_session=<name>`: write the `--codegen incremental` directory to a file in the
+//!   `--out-dir`, an output that differs in every compilation.
+//! - `deny_unused=<name>`: fail with an error that the crate doesn't use `name` when run with
+//!   `-W unused-crate-dependencies`, like a crate with `#![deny(warnings)]`.
+//! - `signal=<n>`: kill itself with the signal.
+//! - `exit=<n>`: exit with the code.
+
+use std::io::Write;
+
+extern "C" {
+    fn raise(signal: i32) -> i32;
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args[1..] == ["-vV"] {
+        println!("rustc 0.0.0-fake\nhost: fake");
+        return;
+    }
+    let config = std::fs::read_to_string(format!("{}.conf", args[0])).unwrap_or_default();
+    let mut exit = 0;
+    for line in config.lines() {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = parts.next().unwrap_or("");
+        match key {
+            "record" => {
+                let dir = std::path::Path::new(value);
+                std::fs::create_dir_all(dir).unwrap();
+                std::fs::write(dir.join("args"), args[1..].join("\n")).unwrap();
+                let env: Vec<String> = std::env::vars()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                std::fs::write(dir.join("env"), env.join("\n")).unwrap();
+            }
+            "stderr" => eprint!("{}", value.replace("\\n", "\n")),
+            "stderr_hex" => {
+                let bytes: Vec<u8> = (0..value.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+                    .collect();
+                std::io::stderr().write_all(&bytes).unwrap();
+            }
+            "sleep_ms" => {
+                std::thread::sleep(std::time::Duration::from_millis(value.parse().unwrap()))
+            }
+            "alloc_mb" => {
+                let memory = vec![1u8; value.parse::<usize>().unwrap() * 1024 * 1024];
+                // Read every page back, so the allocation can't be optimized away.
+                for page in memory.chunks(4096) {
+                    unsafe { std::ptr::read_volatile(&page[0]) };
+                }
+            }
+            "write" => std::fs::write(value, "fake").unwrap(),
+            "output" | "output_session" => {
+                let out_dir = args.iter().find_map(|arg| arg.strip_prefix("--out-dir="));
+                let path = std::path::Path::new(out_dir.unwrap()).join(value);
+                let contents = if key == "output" {
+                    format!("{}: src/lib.rs\n", path.display())
+                } else {
+                    args.iter()
+                        .find_map(|arg| arg.strip_prefix("incremental="))
+                        .unwrap_or("")
+                        .to_string()
+                };
+                std::fs::write(path, contents).unwrap();
+            }
+            "deny_unused" => {
+                if args
+                    .windows(2)
+                    .any(|pair| pair == ["-W", "unused-crate-dependencies"])
+                {
+                    eprintln!("error: extern crate `{}` is unused in crate `foo`\n", value);
+                    exit = 1;
+                }
+            }
+            "signal" => unsafe {
+                raise(value.parse().unwrap());
+            },
+            "exit" => exit = value.parse().unwrap(),
+            _ => panic!("unknown fake rustc option {}", key),
+        }
+    }
+    std::process::exit(exit);
+}
@@ -0,0 +1,173 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use protobuf::CodedInputStream;
+use protobuf::CodedOutputStream;
+use protobuf::Message;
+use rustc_worker::WorkRequest;
+use rustc_worker::WorkResponse;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Once;
+
+static BUILD_FAKE_RUSTC: Once = Once::new();
+
+/// The directory of this test run, which the tests keep their files in.
+fn scratch_root() -> PathBuf {
+    std::env::temp_dir().join(format!("rustc-worker-tests-{}", std::process::id()))
+}
+
+/// The fake rustc binary, compiled from `fake_rustc.rs` on first use.
+fn fake_rustc_binary() -> PathBuf {
+    let binary = scratch_root().join("fake-rustc");
+    BUILD_FAKE_RUSTC.call_once(|| {
+        std::fs::create_dir_all(scratch_root()).unwrap();
+        let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let status = Command::new(rustc)
+            .args(&["--edition", "2018", "-o"])
+            .arg(&binary)
+            .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/common/fake_rustc.rs"))
+            .status()
+            .expect("run rustc");
+        assert!(status.success(), "could not build the fake rustc");
+    });
+    binary
+}
+
+/// A copy of the fake rustc in a directory of its own, so each test configures its own.
+pub struct FakeRustc {
+    dir: PathBuf,
+    config: Vec<String>,
+}
+
+impl FakeRustc {
+    pub fn new(test_name: &str) -> Self {
+        let dir = scratch_root().join(test_name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(fake_rustc_binary(), dir.join("rustc")).unwrap();
+        let fake = FakeRustc {
+            dir,
+            config: Vec::new(),
+        };
+        fake.write_config();
+        fake
+    }
+
+    fn write_config(&self) {
+        std::fs::write(self.dir.join("rustc.conf"), self.config.join("\n")).unwrap();
+    }
+
+    fn set(mut self, key: &str, value: &str) -> Self {
+        self.config.push(format!("{}={}", key, value));
+        self.write_config();
+        self
+    }
+
+    /// Records the arguments and environment of every run, see `args` and `env`.
+    pub fn record(self) -> Self {
+        let dir = self.dir.join("record");
+        self.set("record", dir.to_str().unwrap())
+    }
+
+    pub fn stderr(self, text: &str) -> Self {
+        self.set("stderr", &text.replace('\n', "\\n"))
+    }
+
+    pub fn stderr_bytes(self, bytes: &[u8]) -> Self {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.set("stderr_hex", &hex)
+    }
+
+    pub fn sleep_ms(self, ms: u64) -> Self {
+        self.set("sleep_ms", &ms.to_string())
+    }
+
+    pub fn alloc_mb(self, mb: usize) -> Self {
+        self.set("alloc_mb", &mb.to_string())
+    }
+
+    pub fn write(self, path: &Path) -> Self {
+        self.set("write", path.to_str().unwrap())
+    }
+
+    pub fn output(self, name: &str) -> Self {
+        self.set("output", name)
+    }
+
+    pub fn output_session(self, name: &str) -> Self {
+        self.set("output_session", name)
+    }
+
+    pub fn deny_unused(self, name: &str) -> Self {
+        self.set("deny_unused", name)
+    }
+
+    pub fn signal(self, signal: i32) -> Self {
+        self.set("signal", &signal.to_string())
+    }
+
+    pub fn exit(self, code: i32) -> Self {
+        self.set("exit", &code.to_string())
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("rustc")
+    }
+
+    /// A scratch directory for the test.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The arguments of the last run.
+    pub fn args(&self) -> Vec<String> {
+        let args = std::fs::read_to_string(self.dir.join("record/args")).unwrap();
+        args.lines().map(String::from).collect()
+    }
+
+    /// The environment of the last run.
+    pub fn env(&self) -> Vec<String> {
+        let env = std::fs::read_to_string(self.dir.join("record/env")).unwrap();
+        env.lines().map(String::from).collect()
+    }
+}
+
+pub fn request(args: &[&str]) -> WorkRequest {
+    let mut request = WorkRequest::default();
+    for arg in args {
+        request.mut_arguments().push(arg.to_string());
+    }
+    request
+}
+
+/// Frames requests the way Bazel writes them to a worker's stdin.
+pub fn frame(requests: &[WorkRequest]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+        let mut stream = CodedOutputStream::vec(&mut bytes);
+        for request in requests {
+            stream.write_raw_varint32(request.compute_size()).unwrap();
+            request.write_to_with_cached_sizes(&mut stream).unwrap();
+        }
+        stream.flush().unwrap();
+    }
+    bytes
+}
+
+/// Reads all responses a worker wrote to its stdout.
+pub fn unframe(bytes: &[u8]) -> Vec<WorkResponse> {
+    let mut stream = CodedInputStream::from_bytes(bytes);
+    let mut responses = Vec::new();
+    while !stream.eof().unwrap() {
+        let len = stream.read_raw_varint32().unwrap();
+        let limit = stream.push_limit(u64::from(len)).unwrap();
+        let mut response = WorkResponse::default();
+        response.merge_from(&mut stream).unwrap();
+        stream.pop_limit(limit);
+        responses.push(response);
+    }
+    responses
+}
@@ -0,0 +1,743 @@
+mod common;
+
+use common::FakeRustc;
+use protobuf::Message;
+use rustc_worker::Worker;
+use rustc_worker::WorkerClient;
+use std::io::Write;
+use std::time::Duration;
+
+fn worker(fake: &FakeRustc) -> Worker {
+    Worker::new(fake.path(), fake.path(), "fastbuild").unwrap()
+}
+
+/// Runs the requests through `main_loop` and returns the responses.
+fn run(worker: &Worker, requests: &[rustc_worker::WorkRequest]) -> Vec<rustc_worker::WorkResponse> {
+    let input = common::frame(requests);
+    let mut output = Vec::new();
+    worker.main_loop(&mut &input[..], &mut output).unwrap();
+    common::unframe(&output)
+}
+
+/// Runs the requests through the worker binary, started with one more environment variable.
+fn run_with_env(
+    fake: &FakeRustc,
+    mode: &str,
+    (name, value): (&str, &std::ffi::OsStr),
+    requests: &[rustc_worker::WorkRequest],
+) -> Vec<rustc_worker::WorkResponse> {
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rustc-worker"))
+        .arg(fake.path())
+        .arg(fake.path())
+        .args(&[mode, "--persistent_worker"])
+        .env(name, value)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&common::frame(requests)).unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    common::unframe(&output.stdout)
+}
+
+#[test]
+fn test_cache_dir() {
+    let fake = FakeRustc::new("test_cache_dir");
+    let worker = worker(&fake);
+    let dir = worker.incremental_dir().to_owned();
+    assert!(dir.is_dir());
+    assert_eq!(dir.parent(), Some(std::env::temp_dir().as_path()));
+    let name = dir.file_name().unwrap().to_str().unwrap();
+    let hash = name
+        .strip_prefix("rustc-worker-")
+        .and_then(|rest| rest.strip_suffix("-fastbuild"))
+        .unwrap();
+    assert!(hash.parse::<u64>().is_ok(), "{}", name);
+    assert!(dir.join("manifest.json").is_file());
+    let mode = std::os::unix::fs::PermissionsExt::mode(&dir.metadata().unwrap().permissions());
+    assert_eq!(mode & 0o777, 0o700);
+
+    // The same rustc shares the cache, other compilation modes do not.
+    assert_eq!(worker_dir(&fake, "fastbuild"), dir);
+    let opt = worker_dir(&fake, "opt");
+    assert_ne!(opt, dir);
+    std::fs::remove_dir_all(opt).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cache_dir_per_checkout() {
+    let fake = FakeRustc::new("test_cache_dir_per_checkout");
+    let checkout = |output_base: &str| {
+        let options = rustc_worker::Options {
+            output_base: Some(fake.dir().join(output_base)),
+            ..Default::default()
+        };
+        Worker::with_options(fake.path(), fake.path(), "fastbuild", options)
+            .unwrap()
+            .incremental_dir()
+            .to_owned()
+    };
+    let first = checkout("first");
+    let second = checkout("second");
+    assert_ne!(first, second);
+    std::fs::remove_dir_all(first).unwrap();
+    std::fs::remove_dir_all(second).unwrap();
+}
+
+#[test]
+fn test_cache_dir_symlink() {
+    let fake = FakeRustc::new("test_cache_dir_symlink");
+    // Plant a symlink where the cache would go, as another user could.
+    let shared = worker_dir(&fake, "dbg");
+    std::fs::remove_dir_all(&shared).unwrap();
+    std::os::unix::fs::symlink(fake.dir(), &shared).unwrap();
+
+    let xdg = fake.dir().join("xdg");
+    run_with_env(&fake, "dbg", ("XDG_CACHE_HOME", xdg.as_os_str()), &[]);
+    let dir = xdg.join("rustc-worker").join(shared.file_name().unwrap());
+    assert!(dir.is_dir());
+    assert!(std::fs::symlink_metadata(&shared)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    std::fs::remove_file(shared).unwrap();
+}
+
+fn worker_dir(fake: &FakeRustc, mode: &str) -> std::path::PathBuf {
+    let worker = Worker::new(fake.path(), fake.path(), mode).unwrap();
+    worker.incremental_dir().to_owned()
+}
+
+#[test]
+fn test_incremental_flag() {
+    let fake = FakeRustc::new("test_incremental_flag").record();
+    let worker = worker(&fake);
+    let responses = run(
+        &worker,
+        &[common::request(&["--crate-name", "foo", "src/lib.rs"])],
+    );
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].get_exit_code(), 0);
+    assert_eq!(
+        fake.args(),
+        vec![
+            "--crate-name".to_string(),
+            "foo".to_string(),
+            "src/lib.rs".to_string(),
+            "--codegen".to_string(),
+            format!("incremental={}", worker.incremental_dir().display()),
+        ]
+    );
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_crate_lock() {
+    let fake = FakeRustc::new("test_crate_lock").record();
+    let options = rustc_worker::Options {
+        lock_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    // Hold the lock as another worker compiling the same crate would.
+    let lock = std::fs::File::create(worker.incremental_dir().join("foo.lock")).unwrap();
+    let held = unsafe {
+        libc::flock(
+            std::os::unix::io::AsRawFd::as_raw_fd(&lock),
+            libc::LOCK_EX | libc::LOCK_NB,
+        )
+    };
+    assert_eq!(held, 0);
+
+    let responses = run(
+        &worker,
+        &[common::request(&["--crate-name", "foo", "src/lib.rs"])],
+    );
+    assert_eq!(responses[0].get_exit_code(), 0);
+    assert!(
+        responses[0]
+            .get_output()
+            .contains("could not lock the incremental state of foo"),
+        "{}",
+        responses[0].get_output()
+    );
+    let args = fake.args();
+    let private = args.last().unwrap().strip_prefix("incremental=").unwrap();
+    assert_ne!(std::path::Path::new(private), worker.incremental_dir());
+    assert!(!std::path::Path::new(private).exists());
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_manifest() {
+    let fake = FakeRustc::new("test_manifest");
+    let worker = worker(&fake);
+    run(&worker, &[common::request(&["--crate-name", "foo"])]);
+    let manifest = std::fs::read_to_string(worker.incremental_dir().join("manifest.json")).unwrap();
+    assert!(manifest.contains("\"rustc_version\": \"rustc 0.0.0-fake\\nhost: fake\\n\""));
+    assert!(manifest.contains("\"compilation_mode\": \"fastbuild\""));
+    assert!(manifest.contains("\"foo\": "));
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_history() {
+    let fake = FakeRustc::new("test_history");
+    let worker = worker(&fake);
+    let request = common::request(&["--crate-name", "foo"]);
+    run(&worker, std::slice::from_ref(&request));
+    // What rustc leaves behind after a successful compilation.
+    std::fs::create_dir_all(worker.incremental_dir().join("foo-1a2b3c/s-100-abc-0123")).unwrap();
+    run(&worker, &[request]);
+
+    let history = std::fs::read_to_string(worker.incremental_dir().join("history.jsonl")).unwrap();
+    let lines: Vec<&str> = history.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"crate\":\"foo\",\"kind\":\"cold\""));
+    assert!(lines[1].contains("\"crate\":\"foo\",\"kind\":\"warm\""));
+    let timings = std::fs::read_to_string(worker.incremental_dir().join("timings.jsonl")).unwrap();
+    assert_eq!(timings.lines().count(), 2);
+    assert!(timings.contains("\"crate\":\"foo\",\"crate_types\":[],\"codegen\":true"));
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_sample() {
+    let fake = FakeRustc::new("test_sample").record();
+    let sampled = |sample| {
+        let options = rustc_worker::Options {
+            sample_rate: 1.0,
+            sample,
+            ..Default::default()
+        };
+        let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+        run(&worker, &[common::request(&["--crate-name", "foo"])]);
+        worker
+    };
+
+    sampled(rustc_worker::Sample::Off);
+    assert_eq!(fake.args(), vec!["--crate-name", "foo"]);
+    let worker = sampled(rustc_worker::Sample::Cold);
+    let args = fake.args();
+    let dir = args.last().unwrap().strip_prefix("incremental=").unwrap();
+    assert_ne!(std::path::Path::new(dir), worker.incremental_dir());
+
+    let history = std::fs::read_to_string(worker.incremental_dir().join("history.jsonl")).unwrap();
+    let lines: Vec<&str> = history.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"kind\":\"off\""));
+    assert!(lines[1].contains("\"kind\":\"cold\""));
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_verify() {
+    let verified = |fake: FakeRustc| {
+        let out_dir = fake.dir().join("out");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let options = rustc_worker::Options {
+            verify: rustc_worker::Verify::Error,
+            verify_rate: 1.0,
+            verify_bundles: Some(fake.dir().join("bundles")),
+            ..Default::default()
+        };
+        let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+        let out_dir_arg = format!("--out-dir={}", out_dir.display());
+        let responses = run(
+            &worker,
+            &[common::request(&["--crate-name", "foo", &out_dir_arg])],
+        );
+        std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+        responses.into_iter().next().unwrap()
+    };
+
+    let fake = FakeRustc::new("test_verify").output("foo.d");
+    let response = verified(fake);
+    assert_eq!((response.exit_code, response.output.as_str()), (0, ""));
+
+    let fake = FakeRustc::new("test_verify").output_session("libfoo.rlib");
+    let bundles = fake.dir().join("bundles");
+    let response = verified(fake);
+    assert_eq!(response.exit_code, 1);
+    assert!(response.output.starts_with(
+        "error: the incremental compilation of foo does not match a clean compilation:\n  \
+         0/libfoo.rlib: digest "
+    ));
+    let bundle = std::fs::read_dir(&bundles)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    assert!(bundle.join("incremental/0/libfoo.rlib").is_file());
+    assert!(bundle.join("clean/0/libfoo.rlib").is_file());
+    assert!(bundle.join("report.txt").is_file());
+}
+
+#[test]
+fn test_env() {
+    let fake = FakeRustc::new("test_env").record();
+    let env = ("RUSTC_WORKER_TEST_ENV", "inherited".as_ref());
+    let responses = run_with_env(&fake, "fastbuild", env, &[common::request(&[])]);
+    assert_eq!(responses.len(), 1);
+    assert!(fake
+        .env()
+        .contains(&"RUSTC_WORKER_TEST_ENV=inherited".to_string()));
+    std::fs::remove_dir_all(worker(&fake).incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_output_and_exit_code() {
+    let fake = FakeRustc::new("test_output_and_exit_code")
+        .stderr("error: boom\n")
+        .exit(1);
+    let worker = worker(&fake);
+    let mut first = common::request(&[]);
+    first.set_request_id(3);
+    let mut second = common::request(&[]);
+    second.set_request_id(4);
+    let responses = run(&worker, &[first, second]);
+    assert_eq!(responses.len(), 2);
+    for (response, id) in responses.iter().zip(&[3, 4]) {
+        assert_eq!(response.get_request_id(), *id);
+        assert_eq!(response.get_exit_code(), 1);
+        assert_eq!(response.get_output(), "error: boom\n");
+    }
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_non_utf8_output() {
+    let fake = FakeRustc::new("test_non_utf8_output").stderr_bytes(b"bad \xff\n");
+    let worker = worker(&fake);
+    let responses = run(&worker, &[common::request(&[])]);
+    assert_eq!(responses[0].get_exit_code(), 0);
+    assert_eq!(responses[0].get_output(), "bad \u{fffd}\n");
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_json_diagnostics() {
+    let diagnostic = concat!(
+        r#"{"$message_type":"diagnostic","message":"cannot find value `x`","level":"error","#,
+        r#""rendered":"\u001b[1m\u001b[38;5;9merror\u001b[0m: cannot find value `x`\n"}"#,
+        "\n",
+    );
+    let fake = FakeRustc::new("test_json_diagnostics")
+        .record()
+        .stderr_bytes(diagnostic.as_bytes())
+        .exit(1);
+    let options = rustc_worker::Options {
+        json_diagnostics: true,
+        sarif: Some(fake.dir().join("sarif")),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    let out_dir = format!("--out-dir={}", fake.dir().display());
+    let responses = run(
+        &worker,
+        &[common::request(&["--crate-name", "foo", &out_dir])],
+    );
+    assert_eq!(responses[0].get_exit_code(), 1);
+    assert_eq!(responses[0].get_output(), "error: cannot find value `x`\n");
+    assert!(fake
+        .args()
+        .contains(&"--json=diagnostic-rendered-ansi".to_string()));
+    let sidecar = std::fs::read_to_string(fake.dir().join("foo.diagnostics.json")).unwrap();
+    assert_eq!(sidecar, format!("[\n{}]\n", diagnostic));
+    let sarif = std::fs::read_to_string(fake.dir().join("sarif/foo.sarif")).unwrap();
+    assert!(sarif.contains("\"version\": \"2.1.0\""), "{}", sarif);
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_unused_deps_denied() {
+    // The fake rustc can't force-warn, so the worker asks it to warn, which the crate denies.
+    let fake = FakeRustc::new("test_unused_deps_denied")
+        .record()
+        .deny_unused("dep");
+    let options = rustc_worker::Options {
+        unused_deps_report: Some(fake.dir().join("unused")),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    let out_dir = format!("--out-dir={}", fake.dir().display());
+    let responses = run(
+        &worker,
+        &[common::request(&[
+            "--crate-name=foo",
+            &out_dir,
+            "--extern=dep=libdep.rlib",
+        ])],
+    );
+    assert_eq!(responses[0].get_exit_code(), 0);
+    assert_eq!(responses[0].get_output(), "");
+    assert!(!fake
+        .args()
+        .contains(&"unused-crate-dependencies".to_string()));
+    let report = std::fs::read_to_string(fake.dir().join("unused/foo.json")).unwrap();
+    let unused = &report[report.find("\"unused\"").unwrap()..];
+    assert!(unused.contains("\"dep\""), "{}", report);
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_workspace_root() {
+    // The worker runs rustc in its own directory, as Bazel runs it in the execroot.
+    let cwd = std::env::current_dir().unwrap();
+    let fake = FakeRustc::new("test_workspace_root").stderr(&format!(
+        "error: boom\n --> {}/src/lib.rs:1:2\n",
+        cwd.display()
+    ));
+    let options = rustc_worker::Options {
+        workspace_root: Some(fake.dir().join("workspace")),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    let responses = run(&worker, &[common::request(&[])]);
+    assert_eq!(
+        responses[0].get_output(),
+        "error: boom\n --> src/lib.rs:1:2\n"
+    );
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_check() {
+    let diagnostic = concat!(
+        r#"{"$message_type":"diagnostic","message":"unused variable: `x`","level":"warning","#,
+        r#""rendered":"warning: unused variable: `x`\n"}"#,
+    );
+    let fake = FakeRustc::new("test_check")
+        .record()
+        .stderr_bytes(format!("{}\n", diagnostic).as_bytes());
+    let worker = worker(&fake);
+    let out_dir = format!("--out-dir={}", fake.dir().join("out").display());
+    let responses = run(
+        &worker,
+        &[common::request(&[
+            "--crate-name",
+            "foo",
+            "--emit=link",
+            &out_dir,
+            "src/lib.rs",
+        ])],
+    );
+    assert_eq!(responses[0].get_exit_code(), 0);
+
+    let mut out = Vec::new();
+    let code = rustc_worker::check::check(
+        "foo",
+        &[worker.incremental_dir().to_owned()],
+        None,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", diagnostic));
+    let args = fake.args();
+    assert!(args.contains(&"--emit=metadata".to_string()), "{:?}", args);
+    assert!(!args.contains(&"--emit=link".to_string()), "{:?}", args);
+    assert!(!args.contains(&out_dir), "{:?}", args);
+    let incremental = format!(
+        "incremental={}",
+        worker.incremental_dir().join("check").display()
+    );
+    assert!(args.contains(&incremental), "{:?}", args);
+    assert!(rustc_worker::check::check(
+        "bar",
+        &[worker.incremental_dir().to_owned()],
+        None,
+        &mut Vec::new()
+    )
+    .is_err());
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_signal() {
+    let fake = FakeRustc::new("test_signal").stderr("partial\n").signal(9);
+    let worker = worker(&fake);
+    let responses = run(&worker, &[common::request(&[])]);
+    assert_eq!(responses[0].get_exit_code(), 128 + 9);
+    assert_eq!(
+        responses[0].get_output(),
+        "partial\nrustc-worker: rustc was killed by signal 9\n"
+    );
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_timeout() {
+    let fake = FakeRustc::new("test_timeout")
+        .stderr("partial\n")
+        .sleep_ms(10_000);
+    let options = rustc_worker::Options {
+        timeout: Some(Duration::from_secs(60)),
+        crate_timeouts: vec![("foo".to_string(), Duration::from_millis(200))],
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    // What rustc leaves behind when it is killed in the middle of a session.
+    let crate_dir = worker.incremental_dir().join("foo-1a2b3c");
+    std::fs::create_dir_all(crate_dir.join("s-100-abc-working")).unwrap();
+    std::fs::write(crate_dir.join("s-100-abc.lock"), "").unwrap();
+    std::fs::create_dir_all(crate_dir.join("s-50-def-0123")).unwrap();
+
+    let start = std::time::Instant::now();
+    let responses = run(&worker, &[common::request(&["--crate-name", "foo"])]);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(responses[0].get_exit_code(), 124);
+    assert_eq!(
+        responses[0].get_output(),
+        "partial\nrustc-worker: rustc did not finish within 200ms and was killed\n"
+    );
+    assert!(!crate_dir.join("s-100-abc-working").exists());
+    assert!(!crate_dir.join("s-100-abc.lock").exists());
+    assert!(crate_dir.join("s-50-def-0123").exists());
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_memory_limit() {
+    let fake = FakeRustc::new("test_memory_limit").alloc_mb(256);
+    let options = rustc_worker::Options {
+        memory_limit: Some(64 * 1024 * 1024),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    let responses = run(&worker, &[common::request(&["--crate-name", "foo"])]);
+    assert_eq!(responses[0].get_exit_code(), 128 + 6);
+    assert!(
+        responses[0]
+            .get_output()
+            .ends_with("rustc-worker: rustc ran out of memory (memory limit 64 MiB)\n"),
+        "{}",
+        responses[0].get_output()
+    );
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_spawn_failure() {
+    let fake = FakeRustc::new("test_spawn_failure");
+    let worker = worker(&fake);
+    std::fs::remove_file(fake.path()).unwrap();
+    // Each request fails on its own, and the worker keeps going.
+    let responses = run(&worker, &[common::request(&[]), common::request(&[])]);
+    assert_eq!(responses.len(), 2);
+    for response in responses {
+        assert_eq!(response.get_exit_code(), 1);
+        assert!(
+            response
+                .get_output()
+                .starts_with("rustc-worker: could not run rustc: "),
+            "{}",
+            response.get_output()
+        );
+    }
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_stats() {
+    let fake = FakeRustc::new("test_stats").stderr("warning: foo\n");
+    let stats = fake.dir().join("stats.jsonl");
+    let options = rustc_worker::Options {
+        stats: Some(stats.clone()),
+        ..Default::default()
+    };
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options).unwrap();
+    let quiet = common::request(&["--crate-name", "foo"]);
+    let mut verbose = common::request(&["--crate-name", "bar"]);
+    verbose.set_request_id(1);
+    // What Bazel sends with --worker_verbose.
+    verbose.mut_unknown_fields().add_varint(5, 10);
+    let responses = run(&worker, &[quiet, verbose]);
+    assert_eq!(responses[0].get_output(), "warning: foo\n");
+    assert!(
+        responses[1]
+            .get_output()
+            .starts_with("warning: foo\nrustc-worker: bar took "),
+        "{}",
+        responses[1].get_output()
+    );
+
+    let stats = std::fs::read_to_string(stats).unwrap();
+    let lines: Vec<&str> = stats.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"request_id\":0,\"crate\":\"foo\",\"exit_code\":0"));
+    assert!(lines[1].contains("\"crate\":\"bar\""));
+    assert!(lines[1].contains("\"max_rss_bytes\":"));
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_trace() {
+    let fake = FakeRustc::new("test_trace").sleep_ms(20);
+    let trace = fake.dir().join("trace.json");
+    let options = rustc_worker::Options {
+        trace: Some(trace.clone()),
+        ..Default::default()
+    };
+    // Two workers appending to the same trace.
+    let first =
+        Worker::with_options(fake.path(), fake.path(), "fastbuild", options.clone()).unwrap();
+    let second = Worker::with_options(fake.path(), fake.path(), "opt", options).unwrap();
+    run(&first, &[common::request(&["--crate-name", "foo"])]);
+    run(&second, &[common::request(&["--crate-name", "bar"])]);
+
+    let trace = std::fs::read_to_string(trace).unwrap();
+    assert!(trace.starts_with("[\n"));
+    assert_eq!(trace.matches('[').count(), 1, "{}", trace);
+    assert!(trace.ends_with("},\n"));
+    assert_eq!(trace.matches("\"name\":\"process_name\"").count(), 2);
+    assert!(trace.contains("\"name\":\"foo\",\"cat\":\"rustc-worker\",\"ph\":\"X\""));
+    assert!(trace.contains("\"name\":\"bar\""));
+    assert!(trace.contains("\"crate\":\"foo\",\"request_id\":0"));
+    assert!(trace.contains("\"incremental\":true,\"exit_code\":0"));
+    assert_eq!(trace.matches("\"name\":\"rustc\"").count(), 2);
+    std::fs::remove_dir_all(first.incremental_dir()).unwrap();
+    std::fs::remove_dir_all(second.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_replay() {
+    let fake = FakeRustc::new("test_replay").write(std::path::Path::new("output"));
+    let (first, second) = (fake.dir().join("first"), fake.dir().join("second"));
+    std::fs::create_dir(&first).unwrap();
+    std::fs::create_dir(&second).unwrap();
+    let start = |pid, cwd: &std::path::Path| {
+        format!(
+            "{{\"time_ms\":0,\"pid\":{},\"start\":{{\"program\":{:?},\"rustc\":{:?},\
+             \"compilation_mode\":\"fastbuild\",\"cwd\":{:?}}}}}\n",
+            pid,
+            fake.path(),
+            fake.path(),
+            cwd
+        )
+    };
+    let request = |pid, cwd: &std::path::Path| {
+        format!(
+            "{{\"time_ms\":0,\"pid\":{},\"request\":{{\"arguments\":[],\"inputs\":[],\
+             \"request_id\":0}},\"cwd\":{:?}}}\n\
+             {{\"time_ms\":0,\"pid\":{},\"response\":{{\"exit_code\":0,\"output\":\"\",\
+             \"request_id\":0}}}}\n",
+            pid, cwd, pid
+        )
+    };
+    // Two workers in different directories, writing to the same log.
+    let log = fake.dir().join("log.jsonl");
+    let entries = [
+        start(1, &first),
+        start(2, &second),
+        request(1, &first),
+        request(2, &second),
+    ];
+    std::fs::write(&log, entries.concat()).unwrap();
+    let options = rustc_worker::Options {
+        output_base: Some(fake.dir().to_owned()),
+        ..Default::default()
+    };
+    // The cache real workers would use, which replaying should leave alone.
+    let worker = Worker::with_options(fake.path(), fake.path(), "fastbuild", options.clone());
+    let shared = worker.unwrap().incremental_dir().to_owned();
+    std::fs::remove_dir_all(&shared).unwrap();
+    let mut out = Vec::new();
+    let matched = rustc_worker::replay(&log, options.clone(), &mut out).unwrap();
+    assert!(matched, "{}", String::from_utf8_lossy(&out));
+    assert!(first.join("output").exists());
+    assert!(second.join("output").exists());
+    assert!(!shared.exists());
+
+    let caches = fake.dir().join("caches");
+    std::fs::create_dir(&caches).unwrap();
+    let options = rustc_worker::Options {
+        cache_root: Some(caches.clone()),
+        ..options
+    };
+    assert!(rustc_worker::replay(&log, options, &mut Vec::new()).unwrap());
+    assert_eq!(std::fs::read_dir(&caches).unwrap().count(), 1);
+}
+
+#[test]
+fn test_eof() {
+    let fake = FakeRustc::new("test_eof");
+    let worker = worker(&fake);
+    let mut output = Vec::new();
+    assert!(worker.main_loop(&mut &[][..], &mut output).is_ok());
+    assert!(output.is_empty());
+
+    // A message cut short is an error rather than a shutdown.
+    let input = common::frame(&[common::request(&["--crate-name", "foo"])]);
+    let truncated = &input[..input.len() - 1];
+    assert!(worker.main_loop(&mut &truncated[..], &mut output).is_err());
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_once_with_response_file() {
+    let fake = FakeRustc::new("test_once_with_response_file")
+        .record()
+        .exit(3);
+    let worker = worker(&fake);
+    let response_file = fake.dir().join("params");
+    std::fs::write(&response_file, "--crate-name\nfoo\nsrc/lib.rs\n").unwrap();
+    let status = worker.once_with_response_file(&response_file).unwrap();
+    assert_eq!(status.code(), Some(3));
+    // Without a persistent worker there is no incremental cache to use.
+    assert_eq!(fake.args(), vec!["--crate-name", "foo", "src/lib.rs"]);
+    std::fs::remove_dir_all(worker.incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_worker_client() {
+    let output = std::env::temp_dir().join("rustc-worker-test-client-output");
+    let fake = FakeRustc::new("test_worker_client")
+        .stderr("warning: fine\n")
+        .write(&output);
+    let path = fake.path();
+    let mut client = WorkerClient::spawn(
+        env!("CARGO_BIN_EXE_rustc-worker"),
+        [&path, &path, std::path::Path::new("fastbuild")],
+    )
+    .unwrap();
+
+    // Multiplexed responses can be waited for in any order.
+    let mut first = common::request(&[]);
+    first.set_request_id(1);
+    let mut second = common::request(&[]);
+    second.set_request_id(2);
+    client.send(&first).unwrap();
+    client.send(&second).unwrap();
+    let timeout = Duration::from_secs(30);
+    assert_eq!(client.wait(2, timeout).unwrap().get_request_id(), 2);
+    let response = client.wait(1, timeout).unwrap();
+    assert_eq!(response.get_output(), "warning: fine\n");
+    assert!(output.exists());
+    assert!(client.shutdown().unwrap().success());
+
+    std::fs::remove_file(output).unwrap();
+    std::fs::remove_dir_all(worker(&fake).incremental_dir()).unwrap();
+}
+
+#[test]
+fn test_worker_client_timeout() {
+    let fake = FakeRustc::new("test_worker_client_timeout").sleep_ms(2000);
+    let path = fake.path();
+    let mut client = WorkerClient::spawn(
+        env!("CARGO_BIN_EXE_rustc-worker"),
+        [&path, &path, std::path::Path::new("fastbuild")],
+    )
+    .unwrap();
+    let error = client
+        .request(&common::request(&[]), Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    drop(client);
+    std::fs::remove_dir_all(worker(&fake).incremental_dir()).unwrap();
+}